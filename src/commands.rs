@@ -0,0 +1,652 @@
+use crate::{
+    metrics::Metrics,
+    parse_content, render_report,
+    state::{State, StateStore},
+    Outgoing, ParseOptions, ReportOptions, Sink, RUNTIME_FEATURES,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use teloxide::{
+    net::Download,
+    prelude::*,
+    types::{MessageId, ParseMode},
+    utils::command::BotCommands,
+};
+
+/// Largest file `/preview` will download.
+const MAX_PREVIEW_BYTES: u32 = 1024 * 1024;
+
+/// These commands are supported:
+#[derive(BotCommands, Clone, Debug, PartialEq)]
+#[command(rename_rule = "lowercase")]
+pub enum Command {
+    /// Send match reports to this chat.
+    Subscribe,
+    /// Stop sending match reports to this chat.
+    Unsubscribe,
+    /// Show runtime counters (bot admins only).
+    Metrics,
+    /// Post a stored match report again, e.g. /match 12 (lists recent ids without one).
+    Match(String),
+    /// Post the most recent match report again.
+    LastMatch,
+    /// Render an XML file without posting it: reply to an upload, or name a file in --preview-dir (bot admins only).
+    Preview(String),
+    /// Switch a report feature while the bot runs, e.g. /set compact on (bot admins only).
+    Set(String),
+}
+
+/// Telegram user ids allowed to run bot-wide commands (`--admin-id`).
+#[derive(Debug, Clone, Default)]
+pub struct Admins(pub Vec<UserId>);
+
+impl Admins {
+    fn contains(&self, msg: &Message) -> bool {
+        msg.from.as_ref().is_some_and(|u| self.0.contains(&u.id))
+    }
+}
+
+/// The `--chat-id` targets, which together with the subscribed chats are
+/// the only chats stored reports may be posted in.
+#[derive(Debug, Clone, Default)]
+pub struct ReportChats(pub Vec<ChatId>);
+
+/// What `/preview` needs besides the live report options.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    pub parse_opts: ParseOptions,
+    /// The only folder `/preview <file>` may read from; off when unset.
+    pub local_dir: Option<PathBuf>,
+}
+
+/// Group admins only; anyone can manage their own private chat.
+async fn is_chat_admin(bot: &Bot, msg: &Message) -> ResponseResult<bool> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+    let Some(user) = &msg.from else {
+        return Ok(false);
+    };
+    let member = bot.get_chat_member(msg.chat.id, user.id).await?;
+    Ok(member.is_privileged())
+}
+
+// dptree injects each dependency as its own argument
+#[allow(clippy::too_many_arguments)]
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    store: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    admins: Admins,
+    report_chats: ReportChats,
+    preview: Preview,
+    report_opts: Arc<Mutex<ReportOptions>>,
+) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    log::info!("{:?} from chat {}", cmd, chat_id);
+
+    let reply = match cmd {
+        Command::Subscribe | Command::Unsubscribe => {
+            if !is_chat_admin(&bot, &msg).await? {
+                bot.send_message(chat_id, "Only chat admins can do that.")
+                    .await?;
+                return Ok(());
+            }
+            match store.update(|s| match &cmd {
+                Command::Unsubscribe => s.unsubscribe(chat_id),
+                _ => s.subscribe(chat_id),
+            }) {
+                Ok(changed) => subscription_reply(&cmd, changed).to_string(),
+                Err(e) => {
+                    log::error!("Failed to save state: {}", e);
+                    "Failed to save the subscription, check the bot logs.".to_string()
+                }
+            }
+        }
+        Command::Metrics => {
+            if !admins.contains(&msg) {
+                bot.send_message(chat_id, "Only bot admins can do that.")
+                    .await?;
+                return Ok(());
+            }
+            metrics.summary()
+        }
+        Command::Set(arg) => {
+            if !admins.contains(&msg) {
+                bot.send_message(chat_id, "Only bot admins can do that.")
+                    .await?;
+                return Ok(());
+            }
+            set_reply(&mut report_opts.lock().unwrap(), &arg)
+        }
+        Command::Preview(arg) => {
+            if !admins.contains(&msg) {
+                bot.send_message(chat_id, "Only bot admins can do that.")
+                    .await?;
+                return Ok(());
+            }
+            let (reply, parse_mode) = match load_preview(&bot, &msg, &arg, &preview).await {
+                Ok(data) => {
+                    let opts = report_opts.lock().unwrap().clone();
+                    render_preview(&data, &preview, &opts)
+                }
+                Err(reason) => (Outgoing::new(reason), None),
+            };
+            let sink = Sink::Telegram(bot);
+            return send_preview(&sink, chat_id, msg.id, reply, parse_mode).await;
+        }
+        Command::Match(_) | Command::LastMatch => {
+            let (reply, markdown) =
+                store.read(|s| stored_report_reply(s, &cmd, chat_id, &report_chats));
+            let parse_mode = markdown.then_some(ParseMode::MarkdownV2);
+            return send_reply(&Sink::Telegram(bot), chat_id, &reply, parse_mode).await;
+        }
+    };
+    bot.send_message(chat_id, reply).await?;
+    Ok(())
+}
+
+/// Posts a reply the way reports go out: split to Telegram's length limit,
+/// falling back to plain text if MarkdownV2 is rejected.
+async fn send_reply(
+    sink: &Sink,
+    chat_id: ChatId,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+) -> ResponseResult<()> {
+    sink.send_split(chat_id, text, None, parse_mode).await
+}
+
+/// Posts a preview like a report: each part with `send_reply`, then any
+/// full-report attachment as a reply to the `/preview` command.
+async fn send_preview(
+    sink: &Sink,
+    chat_id: ChatId,
+    command: MessageId,
+    preview: Outgoing,
+    parse_mode: Option<ParseMode>,
+) -> ResponseResult<()> {
+    for part in preview.parts() {
+        send_reply(sink, chat_id, part, parse_mode).await?;
+    }
+    match preview.document {
+        Some(doc) => sink.send_document(chat_id, doc, command, None).await,
+        None => Ok(()),
+    }
+}
+
+fn subscription_reply(cmd: &Command, changed: bool) -> &'static str {
+    match (cmd, changed) {
+        (Command::Unsubscribe, true) => "Unsubscribed, no more match reports here.",
+        (Command::Unsubscribe, false) => "This chat wasn't subscribed.",
+        (_, true) => "Subscribed, match reports will be posted here.",
+        (_, false) => "This chat is already subscribed.",
+    }
+}
+
+/// Whether reports are posted in `chat_id`, so a stored one may be posted
+/// there again; anywhere else that would leak it.
+fn receives_reports(state: &State, chat_id: ChatId, report_chats: &ReportChats) -> bool {
+    state.recipients(&report_chats.0).contains(&chat_id)
+}
+
+const NOT_A_REPORT_CHAT: &str = "Match reports aren't posted in this chat.";
+
+/// Answers `/match` or `/lastmatch` in `chat_id`, or refuses outside the
+/// chats that get reports. Returns the text and whether it is MarkdownV2.
+fn stored_report_reply(
+    state: &State,
+    cmd: &Command,
+    chat_id: ChatId,
+    report_chats: &ReportChats,
+) -> (String, bool) {
+    if !receives_reports(state, chat_id, report_chats) {
+        return (NOT_A_REPORT_CHAT.to_string(), false);
+    }
+    match cmd {
+        Command::Match(arg) => match_reply(state, arg),
+        _ => last_match_reply(state),
+    }
+}
+
+/// The stored report for `/match <id>`, or a plain-text listing of recent
+/// ids. Returns the text and whether it is MarkdownV2.
+fn match_reply(state: &State, arg: &str) -> (String, bool) {
+    let arg = arg.trim().trim_start_matches('#');
+    if arg.is_empty() {
+        if state.reports.is_empty() {
+            return ("No match reports stored yet.".to_string(), false);
+        }
+        let recent: Vec<String> = state
+            .reports
+            .iter()
+            .rev()
+            .take(10)
+            .map(|(id, r)| format!("#{} {}", id, r.title))
+            .collect();
+        return (
+            format!("Recent matches:\n{}\nUse /match <id>", recent.join("\n")),
+            false,
+        );
+    }
+    let Ok(id) = arg.parse::<u64>() else {
+        return (format!("'{}' isn't a match id.", arg), false);
+    };
+    match state.reports.get(&id) {
+        Some(report) => (report.text.clone(), report.markdown),
+        None => (format!("No stored match #{}.", id), false),
+    }
+}
+
+/// The newest stored report for `/lastmatch`, with whether it is MarkdownV2.
+fn last_match_reply(state: &State) -> (String, bool) {
+    match state.reports.last_key_value() {
+        Some((_, report)) => (report.text.clone(), report.markdown),
+        None => ("No matches yet.".to_string(), false),
+    }
+}
+
+/// Applies `/set <feature> <on|off>`; without arguments lists every feature
+/// and whether it is on.
+fn set_reply(opts: &mut ReportOptions, arg: &str) -> String {
+    let parts: Vec<&str> = arg.split_whitespace().collect();
+    let (name, on) = match parts.as_slice() {
+        [] => {
+            let states: Vec<String> = RUNTIME_FEATURES
+                .iter()
+                .map(|&name| {
+                    let on = opts.feature(name).is_some_and(|f| *f);
+                    format!("{}: {}", name, if on { "on" } else { "off" })
+                })
+                .collect();
+            return states.join("\n");
+        }
+        [name, "on"] => (*name, true),
+        [name, "off"] => (*name, false),
+        _ => return "Usage: /set <feature> <on|off>".to_string(),
+    };
+    match opts.feature(name) {
+        Some(flag) => {
+            *flag = on;
+            log::info!(
+                "Feature {} switched {}",
+                name,
+                if on { "on" } else { "off" }
+            );
+            format!("{} is now {}.", name, if on { "on" } else { "off" })
+        }
+        None => format!(
+            "Unknown feature '{}', try one of: {}",
+            name,
+            RUNTIME_FEATURES.join(", ")
+        ),
+    }
+}
+
+/// The bytes to preview: the document `/preview` replies to, or a file
+/// named in the argument from `--preview-dir`. `Err` is the reply to send.
+async fn load_preview(
+    bot: &Bot,
+    msg: &Message,
+    arg: &str,
+    preview: &Preview,
+) -> Result<Vec<u8>, String> {
+    let name = arg.trim();
+    if !name.is_empty() {
+        let Some(dir) = &preview.local_dir else {
+            return Err(
+                "Previewing local files is off, start the bot with --preview-dir.".to_string(),
+            );
+        };
+        let path = preview_path(dir, name)
+            .ok_or_else(|| format!("No file '{}' in the preview folder.", name))?;
+        return tokio::fs::read(&path).await.map_err(|e| {
+            log::error!("Unable to read preview file {:?}: {}", path, e);
+            format!("Unable to read '{}'.", name)
+        });
+    }
+
+    let Some(doc) = msg.reply_to_message().and_then(|m| m.document()) else {
+        return Err("Reply /preview to an uploaded XML file, or give a file name.".to_string());
+    };
+    if doc.file.size > MAX_PREVIEW_BYTES {
+        return Err("That file is too large to preview.".to_string());
+    }
+    let mut data = Vec::new();
+    let downloaded = match bot.get_file(doc.file.id.clone()).await {
+        Ok(file) => bot
+            .download_file(&file.path, &mut data)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    downloaded.map(|()| data).map_err(|e| {
+        log::error!("Failed to download preview file: {}", e);
+        "Failed to download that file, check the bot logs.".to_string()
+    })
+}
+
+/// Resolves `name` inside `dir`, refusing anything that ends up outside it.
+fn preview_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let dir = dir.canonicalize().ok()?;
+    let path = dir.join(name).canonicalize().ok()?;
+    (path.starts_with(&dir) && path.is_file()).then_some(path)
+}
+
+/// The report for a previewed file, or why it couldn't be parsed.
+fn render_preview(
+    data: &[u8],
+    preview: &Preview,
+    opts: &ReportOptions,
+) -> (Outgoing, Option<ParseMode>) {
+    let content = String::from_utf8_lossy(data).into_owned();
+    match parse_content(content, &preview.parse_opts) {
+        Ok(m) => (render_report(&m, opts), opts.parse_mode()),
+        Err(e) => (
+            Outgoing::new(format!("Couldn't parse that file: {}", e)),
+            None,
+        ),
+    }
+}
+
+pub async fn run_dispatcher(
+    bot: Bot,
+    store: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    admins: Admins,
+    report_chats: ReportChats,
+    preview: Preview,
+    report_opts: Arc<Mutex<ReportOptions>>,
+) {
+    let handler = Update::filter_message()
+        .filter_command::<Command>()
+        .endpoint(answer);
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![
+            store,
+            metrics,
+            admins,
+            report_chats,
+            preview,
+            report_opts
+        ])
+        .build()
+        .dispatch()
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StoredReport;
+
+    #[test]
+    fn test_match_reply() {
+        let mut state = State::default();
+        assert_eq!(
+            match_reply(&state, ""),
+            ("No match reports stored yet.".to_string(), false)
+        );
+
+        let id = state.next_report_id();
+        state.archive_report(
+            id,
+            StoredReport {
+                title: "TDM on q3dm6".to_string(),
+                text: "*Match concluded*".to_string(),
+                markdown: true,
+            },
+        );
+        assert_eq!(
+            match_reply(&state, &id.to_string()),
+            ("*Match concluded*".to_string(), true)
+        );
+        assert_eq!(match_reply(&state, "#1").0, "*Match concluded*");
+        assert_eq!(match_reply(&state, "99").0, "No stored match #99.");
+        assert_eq!(match_reply(&state, "abc").0, "'abc' isn't a match id.");
+        assert!(match_reply(&state, " ").0.contains("#1 TDM on q3dm6"));
+    }
+
+    #[test]
+    fn test_last_match_reply() {
+        let mut state = State::default();
+        assert_eq!(
+            last_match_reply(&state),
+            ("No matches yet.".to_string(), false)
+        );
+
+        for text in ["first", "second"] {
+            let id = state.next_report_id();
+            state.archive_report(
+                id,
+                StoredReport {
+                    text: text.to_string(),
+                    ..StoredReport::default()
+                },
+            );
+        }
+        assert_eq!(last_match_reply(&state), ("second".to_string(), false));
+        assert_eq!(
+            Command::parse("/lastmatch", "q3bot").unwrap(),
+            Command::LastMatch
+        );
+    }
+
+    #[test]
+    fn test_stored_reports_only_where_reports_go() {
+        let mut state = State::default();
+        let id = state.next_report_id();
+        state.archive_report(
+            id,
+            StoredReport {
+                text: "*Match concluded*".to_string(),
+                markdown: true,
+                ..StoredReport::default()
+            },
+        );
+        let chats = ReportChats(vec![ChatId(-100)]);
+        let refused = (NOT_A_REPORT_CHAT.to_string(), false);
+        let report = ("*Match concluded*".to_string(), true);
+        let by_id = Command::Match("1".to_string());
+        let listing = Command::Match(String::new());
+
+        for cmd in [&Command::LastMatch, &by_id] {
+            let reply = |state: &State, chat| stored_report_reply(state, cmd, ChatId(chat), &chats);
+            assert_eq!(reply(&state, -100), report);
+            assert_eq!(reply(&state, 42), refused);
+        }
+        assert_eq!(
+            stored_report_reply(&state, &listing, ChatId(42), &chats),
+            refused
+        );
+        state.subscribe(ChatId(42));
+        assert_eq!(
+            stored_report_reply(&state, &by_id, ChatId(42), &chats),
+            report
+        );
+
+        // an upgraded group keeps access under its new id only
+        state.migrate_chat(ChatId(-100), ChatId(-1001));
+        let reply = |chat| stored_report_reply(&state, &Command::LastMatch, ChatId(chat), &chats);
+        assert_eq!(reply(-1001), report);
+        assert_eq!(reply(-100), refused);
+    }
+
+    /// A stored report as long as a big CTF scoreboard, in code blocks.
+    fn long_report() -> StoredReport {
+        let block = format!("*Player*\n```\n{}```\n", "Kills: 12\n".repeat(50));
+        StoredReport {
+            title: "CTF on q3ctf1".to_string(),
+            text: block.repeat(20),
+            markdown: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_reply_is_split() {
+        let mut state = State::default();
+        let id = state.next_report_id();
+        state.archive_report(id, long_report());
+        let (text, markdown) = match_reply(&state, "1");
+        assert!(text.chars().count() > crate::MESSAGE_LIMIT);
+
+        let sent = crate::SentLog::default();
+        let sink = Sink::Recording(sent.clone());
+        let parse_mode = markdown.then_some(ParseMode::MarkdownV2);
+        send_reply(&sink, ChatId(1), &text, parse_mode)
+            .await
+            .unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.len() > 1);
+        for (_, chunk) in sent.iter() {
+            assert!(chunk.chars().count() <= crate::MESSAGE_LIMIT);
+            assert!(chunk.matches("```").count().is_multiple_of(2));
+        }
+        let joined: String = sent.iter().map(|(_, c)| c.as_str()).collect();
+        assert_eq!(joined.matches("Kills: 12").count(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_last_match_reply_is_split() {
+        let mut state = State::default();
+        for _ in 0..2 {
+            let id = state.next_report_id();
+            state.archive_report(id, long_report());
+        }
+        let (text, markdown) = last_match_reply(&state);
+        assert!(markdown);
+
+        let sent = crate::SentLog::default();
+        let sink = Sink::Recording(sent.clone());
+        send_reply(&sink, ChatId(1), &text, Some(ParseMode::MarkdownV2))
+            .await
+            .unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.len() > 1);
+        assert!(sent
+            .iter()
+            .all(|(_, chunk)| chunk.chars().count() <= crate::MESSAGE_LIMIT));
+    }
+
+    #[test]
+    fn test_render_preview_from_bytes() {
+        let preview = Preview {
+            parse_opts: ParseOptions::default(),
+            local_dir: None,
+        };
+        let opts = ReportOptions::default();
+        let data = std::fs::read("test.xml").expect("Unable to read test.xml");
+        let (msg, mode) = render_preview(&data, &preview, &opts);
+        assert!(msg.text.starts_with("*Match concluded*\n"));
+        assert_eq!(mode, Some(ParseMode::MarkdownV2));
+
+        let (msg, mode) = render_preview(b"<match><team></match>", &preview, &opts);
+        assert!(msg.text.starts_with("Couldn't parse that file"));
+        assert_eq!(mode, None);
+    }
+
+    #[tokio::test]
+    async fn test_preview_follows_report_options() {
+        let preview = Preview {
+            parse_opts: ParseOptions::default(),
+            local_dir: None,
+        };
+        let data = std::fs::read("test.xml").expect("Unable to read test.xml");
+        let opts = ReportOptions {
+            summary_with_document: true,
+            ..ReportOptions::default()
+        };
+        let (msg, mode) = render_preview(&data, &preview, &opts);
+        assert!(msg.text.starts_with("*TDM on q3dm6*"));
+
+        let sent = crate::SentLog::default();
+        send_preview(
+            &Sink::Recording(sent.clone()),
+            ChatId(1),
+            MessageId(1),
+            msg,
+            mode,
+        )
+        .await
+        .unwrap();
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].1.contains("Match concluded"));
+    }
+
+    #[tokio::test]
+    async fn test_large_preview_is_split() {
+        let preview = Preview {
+            parse_opts: ParseOptions::default(),
+            local_dir: None,
+        };
+        let players: String = (0..60)
+            .map(|i| {
+                format!(
+                    r#"<player name="Player{i}"><stat name="Score" value="{i}"/><stat name="Kills" value="{i}"/>
+<weapon name="RL" hits="5" shots="11" kills="3"/><weapon name="RG" hits="7" shots="20" kills="4"/></player>"#
+                )
+            })
+            .collect();
+        let xml = format!(r#"<match map="q3dm17" type="FFA" duration="900">{players}</match>"#);
+        let (msg, mode) = render_preview(xml.as_bytes(), &preview, &ReportOptions::default());
+        assert_eq!(mode, Some(ParseMode::MarkdownV2));
+        assert!(msg.text.chars().count() > crate::MESSAGE_LIMIT);
+
+        let sent = crate::SentLog::default();
+        send_preview(
+            &Sink::Recording(sent.clone()),
+            ChatId(1),
+            MessageId(1),
+            msg,
+            mode,
+        )
+        .await
+        .unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.len() > 1);
+        assert!(sent
+            .iter()
+            .all(|(_, chunk)| chunk.chars().count() <= crate::MESSAGE_LIMIT));
+    }
+
+    #[test]
+    fn test_set_reply() {
+        let mut opts = ReportOptions::default();
+        assert_eq!(set_reply(&mut opts, "compact on"), "compact is now on.");
+        assert!(opts.compact);
+        assert_eq!(set_reply(&mut opts, " legend  on "), "legend is now on.");
+        assert!(set_reply(&mut opts, "").contains("compact: on\nlegend: on\n"));
+        assert_eq!(set_reply(&mut opts, "compact off"), "compact is now off.");
+        assert!(!opts.compact);
+
+        assert!(
+            set_reply(&mut opts, "superlatives on").starts_with("Unknown feature 'superlatives'")
+        );
+        assert_eq!(
+            set_reply(&mut opts, "compact maybe"),
+            "Usage: /set <feature> <on|off>"
+        );
+    }
+
+    #[test]
+    fn test_preview_path_stays_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let inside = dir.path().join("dumps");
+        std::fs::create_dir(&inside).unwrap();
+        std::fs::write(inside.join("match.xml"), "<match/>").unwrap();
+        std::fs::write(dir.path().join("secret.xml"), "<match/>").unwrap();
+
+        assert_eq!(
+            preview_path(&inside, "match.xml"),
+            Some(inside.join("match.xml").canonicalize().unwrap())
+        );
+        assert_eq!(preview_path(&inside, "../secret.xml"), None);
+        assert_eq!(preview_path(&inside, "missing.xml"), None);
+        assert_eq!(preview_path(&inside, "."), None);
+    }
+}
@@ -0,0 +1,73 @@
+use serde::Deserialize;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::BotError, Args};
+
+/// Settings from `--config-json`, for setups whose secret manager hands out
+/// JSON. Anything also given on the command line is overridden by the flag.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Telegram bot token, used instead of `TELOXIDE_TOKEN`.
+    pub token: Option<String>,
+    pub folder_path: Option<String>,
+    pub chat_id: Option<i64>,
+    pub state_file: Option<PathBuf>,
+}
+
+// hand-written so the token never ends up in a log line
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("folder_path", &self.folder_path)
+            .field("chat_id", &self.chat_id)
+            .field("state_file", &self.state_file)
+            .finish()
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, BotError> {
+        let data = std::fs::read_to_string(path)?;
+        serde_json::from_str(&data)
+            .map_err(|e| BotError::Config(format!("Invalid config file {:?}: {}", path, e)))
+    }
+
+    /// Fills in whatever the command line left unset and hands back the
+    /// token, if the file has one.
+    pub fn apply(self, args: &mut Args) -> Option<String> {
+        args.folder_path = args.folder_path.take().or(self.folder_path);
+        if args.chat_ids.is_empty() {
+            args.chat_ids.extend(self.chat_id.map(|id| id.to_string()));
+        }
+        args.state_file = args.state_file.take().or(self.state_file);
+        self.token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_json_config() {
+        let config: Config = serde_json::from_str(
+            r#"{"token": "123:secret", "folder_path": "/srv/q3/xmlstats", "chat_id": -100}"#,
+        )
+        .unwrap();
+        assert!(!format!("{:?}", config).contains("secret"));
+
+        let mut args = Args::parse_from(["q3-stats-bot", "--chat-id", "-200"]);
+        assert_eq!(config.apply(&mut args).as_deref(), Some("123:secret"));
+        assert_eq!(args.folder_path.as_deref(), Some("/srv/q3/xmlstats"));
+        // the flag wins over the file
+        assert_eq!(args.chat_ids, ["-200"]);
+
+        assert!(serde_json::from_str::<Config>(r#"{"folder": "/tmp"}"#).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+use crate::{error::BotError, Match};
+
+/// Creates whatever tables are missing, so it's safe to run on every start.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS matches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    map TEXT NOT NULL,
+    match_type TEXT NOT NULL,
+    duration TEXT NOT NULL,
+    is_team_game INTEGER NOT NULL,
+    played_at TEXT
+);
+CREATE TABLE IF NOT EXISTS teams (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    match_id INTEGER NOT NULL REFERENCES matches(id),
+    name TEXT NOT NULL,
+    color TEXT NOT NULL,
+    score TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS players (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    match_id INTEGER NOT NULL REFERENCES matches(id),
+    team_id INTEGER NOT NULL REFERENCES teams(id),
+    name TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS stats (
+    player_id INTEGER NOT NULL REFERENCES players(id),
+    name TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS weapons (
+    player_id INTEGER NOT NULL REFERENCES players(id),
+    name TEXT NOT NULL,
+    hits INTEGER NOT NULL,
+    shots INTEGER NOT NULL,
+    kills INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS map_records (
+    map TEXT PRIMARY KEY,
+    score REAL NOT NULL,
+    player TEXT NOT NULL
+);
+";
+
+/// The best single-player score stored for `map`, if any match on it had one.
+pub fn map_high_score(conn: &Connection, map: &str) -> rusqlite::Result<Option<f64>> {
+    conn.query_row(
+        "SELECT score FROM map_records WHERE map = ?1",
+        [map],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Every parsed match, kept in SQLite (`--db`) for leaderboards and other
+/// queries over past games.
+#[derive(Debug)]
+pub struct MatchDb {
+    conn: Connection,
+}
+
+impl MatchDb {
+    pub fn open(path: &Path) -> Result<Self, BotError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Closes the connection, reporting what dropping it would swallow.
+    pub fn close(self) -> Result<(), BotError> {
+        self.conn.close().map_err(|(_, e)| e.into())
+    }
+
+    /// Stores the match with its teams, players, stats and weapons in one
+    /// transaction, raising the map record if a player beat it.
+    pub fn insert_match(&mut self, m: &Match) -> Result<Inserted, BotError> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO matches (map, match_type, duration, is_team_game, played_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                m.map,
+                m.match_type,
+                m.duration,
+                m.is_team_game,
+                m.played_at().map(|t| t.to_rfc3339())
+            ],
+        )?;
+        let match_id = tx.last_insert_rowid();
+        for team in &m.teams {
+            tx.execute(
+                "INSERT INTO teams (match_id, name, color, score) VALUES (?1, ?2, ?3, ?4)",
+                params![match_id, team.name, team.color, team.score],
+            )?;
+            let team_id = tx.last_insert_rowid();
+            for player in &team.players {
+                tx.execute(
+                    "INSERT INTO players (match_id, team_id, name) VALUES (?1, ?2, ?3)",
+                    params![match_id, team_id, player.name],
+                )?;
+                let player_id = tx.last_insert_rowid();
+                for (name, value) in &player.stats {
+                    tx.execute(
+                        "INSERT INTO stats (player_id, name, value) VALUES (?1, ?2, ?3)",
+                        params![player_id, name, value],
+                    )?;
+                }
+                for weapon in &player.weapons {
+                    tx.execute(
+                        "INSERT INTO weapons (player_id, name, hits, shots, kills)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            player_id,
+                            weapon.name,
+                            weapon.hits,
+                            weapon.shots,
+                            weapon.kills
+                        ],
+                    )?;
+                }
+            }
+        }
+        let best = m
+            .teams
+            .iter()
+            .flat_map(|t| &t.players)
+            .filter_map(|p| p.score().map(|score| (score, p)))
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        let mut new_record = false;
+        if let Some((score, player)) = best {
+            let previous = map_high_score(&tx, &m.map)?;
+            if previous.is_none_or(|prev| score > prev) {
+                tx.execute(
+                    "INSERT OR REPLACE INTO map_records (map, score, player) VALUES (?1, ?2, ?3)",
+                    params![m.map, score, player.name],
+                )?;
+                // the first match on a map sets its record without beating anyone
+                new_record = previous.is_some();
+            }
+        }
+        tx.commit()?;
+        Ok(Inserted {
+            id: match_id,
+            new_record,
+        })
+    }
+}
+
+/// What `MatchDb::insert_match` stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Inserted {
+    pub id: i64,
+    /// A player beat the previous best score on the map.
+    pub new_record: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_content, ParseOptions};
+
+    #[test]
+    fn test_insert_parsed_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("matches.db");
+        let data = std::fs::read_to_string("test.xml").unwrap();
+        let m = parse_content(data, &ParseOptions::default()).unwrap();
+        let players = m.teams.iter().map(|t| t.players.len() as i64).sum::<i64>();
+
+        let mut db = MatchDb::open(&path).unwrap();
+        let id = db.insert_match(&m).unwrap().id;
+        assert_eq!(db.insert_match(&m).unwrap().id, id + 1);
+        drop(db);
+
+        // reopening runs the migration again over the existing tables
+        let db = MatchDb::open(&path).unwrap();
+        let count: i64 = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM players WHERE match_id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, players);
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_map_high_score() {
+        let score = |value: &str| {
+            let xml = format!(
+                r#"<match map="q3dm6" type="FFA" duration="300">
+<player name="A"><stat name="Score" value="{value}"/></player>
+<player name="B"><stat name="Score" value="2"/></player>
+</match>"#
+            );
+            parse_content(xml, &ParseOptions::default()).unwrap()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = MatchDb::open(&dir.path().join("matches.db")).unwrap();
+        assert_eq!(map_high_score(&db.conn, "q3dm6").unwrap(), None);
+
+        assert!(!db.insert_match(&score("10")).unwrap().new_record);
+        assert_eq!(map_high_score(&db.conn, "q3dm6").unwrap(), Some(10.0));
+        // a tie doesn't take the record
+        assert!(!db.insert_match(&score("10")).unwrap().new_record);
+        assert!(db.insert_match(&score("15")).unwrap().new_record);
+        assert!(!db.insert_match(&score("12")).unwrap().new_record);
+        assert_eq!(map_high_score(&db.conn, "q3dm6").unwrap(), Some(15.0));
+        assert_eq!(map_high_score(&db.conn, "q3dm17").unwrap(), None);
+    }
+}
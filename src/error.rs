@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// Failure categories the bot tells apart; `anyhow` is only used in `main`.
+#[derive(Debug)]
+pub enum BotError {
+    /// Bad flags or an environment the operator has to fix.
+    Config(String),
+    /// The folder watcher couldn't be set up or stopped delivering events.
+    Watch(notify::Error),
+    /// A match file that isn't usable XML.
+    Parse(String),
+    /// Telegram rejected or never received a request.
+    Send(teloxide::RequestError),
+    /// The `--db` match database couldn't be opened or written.
+    Db(rusqlite::Error),
+    /// The `--state-file` isn't valid JSON, or the state couldn't be
+    /// turned into it.
+    State(serde_json::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Config(msg) => write!(f, "{}", msg),
+            BotError::Watch(e) => write!(f, "watcher error: {}", e),
+            BotError::Parse(msg) => write!(f, "parse error: {}", msg),
+            BotError::Send(e) => write!(f, "send error: {}", e),
+            BotError::Db(e) => write!(f, "database error: {}", e),
+            BotError::State(e) => write!(f, "state file error: {}", e),
+            BotError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BotError::Config(_) | BotError::Parse(_) => None,
+            BotError::Watch(e) => Some(e),
+            BotError::Send(e) => Some(e),
+            BotError::Db(e) => Some(e),
+            BotError::State(e) => Some(e),
+            BotError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<notify::Error> for BotError {
+    fn from(e: notify::Error) -> Self {
+        BotError::Watch(e)
+    }
+}
+
+impl From<quick_xml::Error> for BotError {
+    fn from(e: quick_xml::Error) -> Self {
+        BotError::Parse(e.to_string())
+    }
+}
+
+impl From<teloxide::RequestError> for BotError {
+    fn from(e: teloxide::RequestError) -> Self {
+        BotError::Send(e)
+    }
+}
+
+impl From<rusqlite::Error> for BotError {
+    fn from(e: rusqlite::Error) -> Self {
+        BotError::Db(e)
+    }
+}
+
+impl From<std::io::Error> for BotError {
+    fn from(e: std::io::Error) -> Self {
+        BotError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(e: serde_json::Error) -> Self {
+        BotError::State(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions_pick_the_variant() {
+        let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(matches!(BotError::from(io), BotError::Io(_)));
+
+        let watch = notify::Error::generic("inotify limit reached");
+        assert!(matches!(BotError::from(watch), BotError::Watch(_)));
+
+        let send = teloxide::RequestError::Api(teloxide::ApiError::ChatNotFound);
+        assert!(matches!(BotError::from(send), BotError::Send(_)));
+
+        let json = serde_json::from_str::<u32>("nope").unwrap_err();
+        assert!(matches!(BotError::from(json), BotError::State(_)));
+    }
+}
@@ -1,13 +1,44 @@
+mod commands;
+mod config;
+mod db;
+mod error;
+mod metrics;
+mod state;
+
 use anyhow::{bail, Result};
-use clap::Parser;
-use notify::{recommended_watcher, EventKind, Watcher};
-use quick_xml::{events::Event, Reader};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, ValueEnum};
+use db::MatchDb;
+use error::BotError;
+use metrics::Metrics;
+use notify::{
+    event::{ModifyKind, RenameMode},
+    recommended_watcher, EventHandler, EventKind, PollWatcher, Watcher,
+};
+use quick_xml::{
+    events::{attributes::Attribute, BytesStart, Event},
+    Reader,
+};
+use serde::Serialize;
+use state::{State, StateStore, StoredReport};
 use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    io::{Read, Write},
     path::{Path, PathBuf},
-    sync::mpsc::channel,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use teloxide::{
+    prelude::*,
+    types::{InputFile, MessageId, ParseMode, ReplyParameters, ThreadId},
+    ApiError, RequestError,
 };
-use teloxide::prelude::*;
+use url::Url;
 
+/// Stats left out of reports unless `--hide-stats`/`--show-stats` say otherwise.
 static BANNED_STATS: [&str; 8] = [
     "MH",
     "RA",
@@ -19,7 +50,36 @@ static BANNED_STATS: [&str; 8] = [
     "Red Flag",
 ];
 
-#[derive(Debug, Default)]
+static WEAPON_NAMES: [(&str, &str); 10] = [
+    ("G", "Gauntlet"),
+    ("MG", "Machinegun"),
+    ("SG", "Shotgun"),
+    ("GL", "Grenade Launcher"),
+    ("RL", "Rocket Launcher"),
+    ("LG", "Lightning Gun"),
+    ("RG", "Railgun"),
+    ("PG", "Plasma Gun"),
+    ("BFG", "BFG10K"),
+    ("GH", "Grappling Hook"),
+];
+
+/// Shown next to a weapon in the `--weapon-meta` block.
+static WEAPON_EMOJIS: [(&str, &str); 9] = [
+    ("G", "👊"),
+    ("MG", "🔫"),
+    ("SG", "💥"),
+    ("GL", "💣"),
+    ("RL", "🚀"),
+    ("LG", "⚡"),
+    ("RG", "🔦"),
+    ("PG", "🔵"),
+    ("BFG", "☢️"),
+];
+
+/// Order used by `--canonical-weapon-order`, roughly as on the weapon bar.
+static CANONICAL_WEAPON_ORDER: [&str; 9] = ["G", "MG", "SG", "GL", "RL", "LG", "RG", "PG", "BFG"];
+
+#[derive(Debug, Default, Clone, Serialize)]
 struct Weapon {
     name: String,
     hits: u32,
@@ -27,394 +87,6850 @@ struct Weapon {
     kills: u32,
 }
 
+impl Weapon {
+    /// Hits over shots as a percentage, rounded to the nearest whole one.
+    /// Worked out in `u64` so huge counts from long matches can't overflow.
+    /// `None` for hits without any shots, which only broken data has.
+    fn accuracy(&self) -> Option<u32> {
+        if self.shots == 0 {
+            return (self.hits == 0).then_some(0);
+        }
+        if self.hits >= self.shots {
+            return Some(100);
+        }
+        let (hits, shots) = (u64::from(self.hits), u64::from(self.shots));
+        // hits < shots here, so this is at most 100
+        Some(((hits * 100 + shots / 2) / shots) as u32)
+    }
+}
+
 #[derive(Debug, Default)]
 struct Player {
     name: String,
     stats: Vec<(String, String)>,
     weapons: Vec<Weapon>,
+    /// What killed this player, from mods that record `<death>` elements.
+    deaths_by_weapon: Vec<(String, u32)>,
+}
+
+impl Player {
+    fn stat(&self, name: &str) -> Option<&str> {
+        self.stats
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Folds stats that appear more than once into a single entry, where the
+    /// first one was.
+    fn merge_duplicate_stats(&mut self, policy: DuplicateStatPolicy) {
+        let mut merged: Vec<(String, Vec<String>)> = Vec::new();
+        for (name, val) in self.stats.drain(..) {
+            match merged.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, vals)) => vals.push(val),
+                None => merged.push((name, vec![val])),
+            }
+        }
+        self.stats = merged
+            .into_iter()
+            .map(|(name, mut vals)| {
+                let val = match policy {
+                    DuplicateStatPolicy::First => vals.swap_remove(0),
+                    DuplicateStatPolicy::Last => vals.pop().unwrap(),
+                    DuplicateStatPolicy::Sum => {
+                        match vals.iter().map(|v| parse_number(v)).sum::<Option<f64>>() {
+                            Some(total) if vals.len() > 1 => total.to_string(),
+                            _ => vals.pop().unwrap(),
+                        }
+                    }
+                };
+                (name, val)
+            })
+            .collect();
+    }
+
+    fn score(&self) -> Option<f64> {
+        self.stat("Score").and_then(parse_number)
+    }
+
+    fn kills(&self) -> u32 {
+        self.stat("Kills").map_or(0, parse_count)
+    }
+
+    fn deaths(&self) -> u32 {
+        self.stat("Deaths").map_or(0, parse_count)
+    }
+
+    /// Hits over shots pooled across every weapon, rounded like a single
+    /// weapon's; `None` without any shots.
+    fn overall_accuracy(&self) -> Option<u32> {
+        let (hits, shots) = self.weapons.iter().fold((0u64, 0u64), |(hits, shots), w| {
+            (
+                hits + u64::from(w.hits.min(w.shots)),
+                shots + u64::from(w.shots),
+            )
+        });
+        (shots > 0).then(|| ((hits * 100 + shots / 2) / shots) as u32)
+    }
+
+    /// The mod's own `Accuracy` stat (`55` or `55%`), when it reports one.
+    fn reported_accuracy(&self) -> Option<u32> {
+        let value = self.stat("Accuracy")?.trim().trim_end_matches('%');
+        parse_number(value).map(|v| v.round().clamp(0.0, 100.0) as u32)
+    }
+
+    /// What the report shows as the player's accuracy: the mod's own stat,
+    /// or else the pooled weapon accuracy.
+    fn shown_accuracy(&self) -> Option<u32> {
+        self.reported_accuracy().or_else(|| self.overall_accuracy())
+    }
+
+    /// Kills per death, counting a deathless player as dying once. `None`
+    /// unless both stats are numbers; they're counted like `kills()`.
+    fn kd_ratio(&self) -> Option<f32> {
+        parse_number(self.stat("Kills")?)?;
+        parse_number(self.stat("Deaths")?)?;
+        Some(self.kills() as f32 / self.deaths().max(1) as f32)
+    }
+
+    /// `(given, taken, net)` damage, rounded, when both stats are numbers.
+    fn damage_balance(&self) -> Option<(i64, i64, i64)> {
+        let given = parse_number(self.stat("DamageGiven")?)?.round() as i64;
+        let taken = parse_number(self.stat("DamageTaken")?)?.round() as i64;
+        Some((given, taken, given - taken))
+    }
 }
 
 #[derive(Debug, Default)]
 struct Team {
+    name: String,
+    color: String,
     score: String,
+    /// Finishing position of a teamless player that has no score.
+    place: Option<u32>,
     players: Vec<Player>,
 }
 
+impl Team {
+    /// The mean or median of the players' accuracies, leaving out players
+    /// without any. An even count takes the mean of the middle two.
+    fn accuracy(&self, stat: TeamAccuracyStat) -> Option<f64> {
+        let mut values: Vec<f64> = self
+            .players
+            .iter()
+            .filter_map(Player::shown_accuracy)
+            .map(f64::from)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        let count = values.len();
+        Some(match stat {
+            TeamAccuracyStat::Mean => values.iter().sum::<f64>() / count as f64,
+            TeamAccuracyStat::Median => {
+                values.sort_by(f64::total_cmp);
+                if count.is_multiple_of(2) {
+                    (values[count / 2 - 1] + values[count / 2]) / 2.0
+                } else {
+                    values[count / 2]
+                }
+            }
+        })
+    }
+}
+
 #[derive(Debug, Default)]
 struct Match {
     map: String,
+    /// Server name from the `server` (or `hostname`) attribute; empty if absent.
+    server: String,
     match_type: String,
+    ruleset: Option<String>,
     duration: String,
     is_team_game: bool,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    /// When the match ended, from the `timestamp` attribute (Unix seconds).
+    timestamp: Option<i64>,
+    /// Modification time of the source file, used when the XML has no times.
+    file_time: Option<DateTime<Utc>>,
+    /// Server limits when the XML has them; zero (no limit) is left out.
+    frag_limit: Option<u32>,
+    /// Minutes.
+    time_limit: Option<u32>,
+    capture_limit: Option<u32>,
     teams: Vec<Team>,
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    folder_path: Option<String>,
-
-    #[arg(short, long, allow_hyphen_values = true)]
-    chat_id: Option<String>,
-
-    #[arg(long)]
-    test_file: Option<PathBuf>,
+/// How a match ended, going by team scores (each player's own in FFA).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    /// Index into `Match::teams` of the one team on the top score.
+    Win(usize),
+    /// The top score is shared, e.g. 3-3, or two FFA players on 20.
+    Draw,
+    /// No teams, or a score that isn't a number.
+    Unknown,
 }
 
-fn escape_markdown(message: &str) -> String {
-    let mut escaped_message = String::new();
-    for c in message.chars() {
-        match c {
-            '*' | '_' | '[' | ']' | '(' | ')' | '~' | '>' | '#' | '+' | '-' | '=' | '|' | '{'
-            | '}' | '.' | '!' => {
-                escaped_message.push('\\');
-            }
-            _ => {}
-        }
-        escaped_message.push(c);
+/// Decides wins and draws for the header, the team markers and
+/// `--report-wins-only` alike.
+fn match_outcome(m: &Match) -> Outcome {
+    let Some(scores) = m
+        .teams
+        .iter()
+        .map(|t| parse_number(&t.score))
+        .collect::<Option<Vec<f64>>>()
+    else {
+        return Outcome::Unknown;
+    };
+    let best = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let mut leaders = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s == best)
+        .map(|(idx, _)| idx);
+    match (leaders.next(), leaders.next()) {
+        (Some(idx), None) => Outcome::Win(idx),
+        (Some(_), Some(_)) => Outcome::Draw,
+        _ => Outcome::Unknown,
     }
-    escaped_message
 }
 
-fn format_duration(duration: &str) -> String {
-    let Ok(total_seconds) = duration.parse::<u64>() else {
-        return duration.to_string();
-    };
-
-    let minutes = total_seconds / 60;
-    let seconds = total_seconds % 60;
-    format!("{minutes}:{seconds:02}")
-}
+impl Match {
+    /// Free-for-all: every player ended up in their own synthetic team.
+    fn is_ffa(&self) -> bool {
+        !self.is_team_game
+            && self.teams.len() > 2
+            && self.teams.iter().all(|t| t.players.len() == 1)
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    if let Some(test_file) = args.test_file {
-        let data = tokio::fs::read_to_string(&test_file).await?;
-        let match_data = parse_content(data)?;
-        println!("{}", format_match_report(&match_data));
-        return Ok(());
+    /// Index of the team with the strictly highest numeric score.
+    fn winning_team(&self) -> Option<usize> {
+        match match_outcome(self) {
+            Outcome::Win(idx) => Some(idx),
+            Outcome::Draw | Outcome::Unknown => None,
+        }
     }
 
-    dotenvy::dotenv().ok();
-    pretty_env_logger::formatted_builder()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-    log::info!("Starting q3reportbot...");
+    /// The top scorer, with ties going to more kills and then fewer deaths;
+    /// the first in XML order if they're level on all three.
+    fn mvp(&self) -> Option<&Player> {
+        let rank = |p: &Player| {
+            (
+                p.score().unwrap_or(f64::NEG_INFINITY),
+                p.kills(),
+                p.deaths(),
+            )
+        };
+        self.teams
+            .iter()
+            .flat_map(|t| &t.players)
+            .reduce(|best, p| {
+                let (best_score, best_kills, best_deaths) = rank(best);
+                let (score, kills, deaths) = rank(p);
+                let order = score
+                    .total_cmp(&best_score)
+                    .then(kills.cmp(&best_kills))
+                    .then(best_deaths.cmp(&deaths));
+                if order == Ordering::Greater {
+                    p
+                } else {
+                    best
+                }
+            })
+    }
 
-    let folder_path = args
-        .folder_path
-        .ok_or_else(|| anyhow::anyhow!("--folder-path is required unless --test-file is used"))?;
-    let chat_id_arg = args
-        .chat_id
-        .ok_or_else(|| anyhow::anyhow!("--chat-id is required unless --test-file is used"))?;
-    let chat_id_val = chat_id_arg
-        .parse::<i64>()
-        .map_err(|e| anyhow::anyhow!("Failed to parse chat_id '{}': {}", chat_id_arg, e))?;
-    let chat_id = ChatId(chat_id_val);
-    let bot = Bot::from_env();
+    fn find_team(&self, name: &str) -> Option<usize> {
+        let wanted = normalize_name(name);
+        self.teams
+            .iter()
+            .position(|t| normalize_name(&t.name) == wanted)
+    }
 
-    log::info!("Monitoring folder: {}", folder_path);
-    log::info!("Target chat ID: {}", chat_id_arg);
+    /// Stable identity of a match regardless of file content: the map plus
+    /// the sorted player names. Live-updated dumps of one match share it.
+    fn identity_key(&self) -> String {
+        let mut names: Vec<String> = self
+            .teams
+            .iter()
+            .flat_map(|t| &t.players)
+            .map(|p| normalize_name(&p.name))
+            .collect();
+        names.sort();
+        format!("{}|{}", self.map, names.join(","))
+    }
 
-    monitor_folder(bot, chat_id, folder_path).await?;
+    /// The identity plus every team score and player `Score`, so two dumps
+    /// share it only when nothing a reader would notice has changed.
+    fn scores_key(&self) -> String {
+        let mut teams: Vec<String> = self
+            .teams
+            .iter()
+            .map(|t| format!("{}={}", normalize_name(&t.name), t.score))
+            .collect();
+        teams.sort();
+        let mut players: Vec<String> = self
+            .teams
+            .iter()
+            .flat_map(|t| &t.players)
+            .map(|p| {
+                let score = p.stat("Score").unwrap_or_default();
+                format!("{}={}", normalize_name(&p.name), score)
+            })
+            .collect();
+        players.sort();
+        format!(
+            "{}|{}|{}",
+            self.identity_key(),
+            teams.join(","),
+            players.join(",")
+        )
+    }
 
-    Ok(())
-}
+    /// The highest numeric team (or FFA player) score, `None` when no score
+    /// parses.
+    fn top_score(&self) -> Option<f64> {
+        self.teams
+            .iter()
+            .filter_map(|t| parse_number(&t.score))
+            .max_by(|a, b| a.total_cmp(b))
+    }
 
-async fn monitor_folder(bot: Bot, chat_id: ChatId, folder_path: String) -> Result<()> {
-    let (tx, rx) = channel();
-    let mut watcher = recommended_watcher(tx)?;
-    let path = Path::new(&folder_path);
+    /// Why the match ended, worked out from the winner's score against the
+    /// limits. A reached score limit wins over the time limit, since the
+    /// clock can run out on the same frame.
+    fn end_reason(&self) -> Option<&'static str> {
+        let top_score = self.top_score();
+        let reached = |limit: Option<u32>| matches!((limit, top_score), (Some(limit), Some(score)) if score >= f64::from(limit));
+        if reached(self.capture_limit) {
+            return Some("capture limit reached");
+        }
+        if reached(self.frag_limit) {
+            return Some("frag limit reached");
+        }
+        let secs = duration_secs(&self.duration)?;
+        self.time_limit
+            .filter(|&limit| secs >= u64::from(limit) * 60)
+            .map(|_| "time limit")
+    }
 
-    watcher.watch(path, notify::RecursiveMode::Recursive)?;
+    fn played_at(&self) -> Option<DateTime<Utc>> {
+        self.end_time
+            .or_else(|| {
+                self.timestamp
+                    .and_then(|ts| DateTime::from_timestamp(ts, 0))
+            })
+            .or(self.start_time)
+            .or(self.file_time)
+    }
 
-    log::info!("Watching for changes in {:?}", path);
+    /// A 0-100 "was it a good match" signal: 60% pace (kills per minute,
+    /// saturating at 3/min) and 40% closeness of the top two scores.
+    /// `None` when the duration is unknown.
+    fn intensity(&self) -> Option<u32> {
+        let secs = duration_secs(&self.duration).filter(|s| *s > 0)?;
+        let minutes = secs as f64 / 60.0;
 
-    loop {
-        match rx.recv() {
-            Ok(event) => match event {
-                Ok(e) => match e.kind {
-                    EventKind::Create(_) => {
-                        if let Some(fpath) = e.paths.last() {
-                            if fpath.is_dir() {
-                                continue;
-                            }
-                            log::info!("New file detected: {:?}", fpath);
+        let total_kills: f64 = self
+            .teams
+            .iter()
+            .flat_map(|t| &t.players)
+            .filter_map(|p| p.stat("Kills").and_then(parse_number))
+            .sum();
+        let pace = (total_kills / minutes / 3.0).clamp(0.0, 1.0);
 
-                            // delay to ensure the file is fully written
-                            tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-
-                            match tokio::fs::read_to_string(fpath).await {
-                                Ok(data) => match parse_content(data) {
-                                    Ok(match_data) => {
-                                        let msg = format_match_report(&match_data);
-                                        if let Err(err) = bot
-                                            .send_message(chat_id, msg)
-                                            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                                            .await
-                                        {
-                                            log::error!("Failed to send message: {}", err);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        log::error!("Error parsing content: {}", e);
-                                    }
-                                },
-                                Err(e) => log::error!("Unable to read file {:?}: {}", fpath, e),
-                            }
-                        }
-                    }
-                    _ => (),
-                },
-                Err(e) => log::error!("Watcher error: {:?}", e),
-            },
-            Err(e) => {
-                log::error!("Channel error: {:?}", e);
-                break;
+        let mut scores: Vec<f64> = self
+            .teams
+            .iter()
+            .filter_map(|t| parse_number(&t.score))
+            .collect();
+        scores.sort_by(|a, b| b.total_cmp(a));
+        let closeness = match scores.as_slice() {
+            [first, second, ..] => {
+                let spread = first.abs() + second.abs();
+                if spread == 0.0 {
+                    1.0
+                } else {
+                    1.0 - ((first - second) / spread).clamp(0.0, 1.0)
+                }
             }
-        }
+            _ => 0.5,
+        };
+
+        Some((100.0 * (0.6 * pace + 0.4 * closeness)).round() as u32)
     }
-    Ok(())
 }
 
-fn parse_content(data: String) -> Result<Match> {
-    let mut reader = Reader::from_str(&data);
-    reader.config_mut().trim_text(true);
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    folder_path: Option<String>,
 
-    let mut buf = Vec::new();
-    let mut game_match = Match::default();
+    /// Chat to post reports to; repeat it or separate ids with commas to
+    /// post to several.
+    #[arg(
+        short,
+        long = "chat-id",
+        allow_hyphen_values = true,
+        value_delimiter = ','
+    )]
+    chat_ids: Vec<String>,
 
-    let mut current_team: Option<Team> = None;
-    let mut current_player: Option<Player> = None;
+    #[arg(long)]
+    test_file: Option<PathBuf>,
 
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Err(e) => bail!("Error at position {}: {:?}", reader.error_position(), e),
-            Ok(Event::Eof) => break,
+    /// Watch and report as usual, but print each message to stdout instead
+    /// of sending it. No bot token is needed.
+    #[arg(long)]
+    dry_run: bool,
 
-            Ok(Event::Start(e)) => match e.name().as_ref() {
-                b"match" => {
-                    for attr in e.attributes().flatten() {
-                        match attr.key.into_inner() {
-                            b"map" => {
-                                game_match.map = String::from_utf8_lossy(&attr.value).into_owned()
-                            }
-                            b"type" => {
-                                game_match.match_type =
-                                    String::from_utf8_lossy(&attr.value).into_owned()
-                            }
-                            b"duration" => {
-                                game_match.duration =
-                                    String::from_utf8_lossy(&attr.value).into_owned()
-                            }
-                            b"isTeamGame" => {
-                                game_match.is_team_game = String::from_utf8_lossy(&attr.value)
-                                    .parse()
-                                    .unwrap_or(false)
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                b"team" => {
-                    let mut team = Team::default();
-                    for attr in e.attributes().flatten() {
-                        if attr.key.into_inner() == b"score" {
-                            team.score = String::from_utf8_lossy(&attr.value).into_owned();
-                        }
-                    }
-                    current_team = Some(team);
-                }
-                b"player" => {
-                    let mut player = Player::default();
-                    for attr in e.attributes().flatten() {
-                        if attr.key.into_inner() == b"name" {
-                            player.name = String::from_utf8_lossy(&attr.value).into_owned();
-                        }
-                    }
-                    current_player = Some(player);
-                }
-                _ => {}
-            },
+    /// Read the bot token, folder, chat id and state file from a JSON file.
+    /// Flags given on the command line take precedence.
+    #[arg(long, value_name = "PATH")]
+    config_json: Option<PathBuf>,
 
-            Ok(Event::End(e)) => match e.name().local_name().as_ref() {
-                b"team" => {
-                    if let Some(team) = current_team.take() {
-                        game_match.teams.push(team);
-                    }
-                }
-                b"player" => {
-                    if let Some(player) = current_player.take() {
-                        if let Some(team) = current_team.as_mut() {
-                            team.players.push(player);
-                        } else {
-                            // player outside of a team (e.g. 1v1 or ffa)
-                            let mut team = Team::default();
-                            if let Some((_, score)) =
-                                player.stats.iter().find(|(n, _)| n == "Score")
-                            {
-                                team.score = score.clone();
-                            }
-                            team.players.push(player);
-                            game_match.teams.push(team);
-                        }
-                    }
-                }
-                _ => {}
-            },
+    /// Bot API server to talk to instead of the public one, e.g. a local
+    /// `telegram-bot-api` at `http://localhost:8081`.
+    #[arg(long, value_name = "URL", value_parser = parse_api_url)]
+    api_url: Option<Url>,
 
-            Ok(Event::Empty(e)) => {
-                let mut attr_map = std::collections::HashMap::new();
-                for attr in e.attributes().flatten() {
-                    attr_map.insert(attr.key.into_inner().to_vec(), attr.value.to_vec());
-                }
+    /// Poll the folder every N milliseconds instead of relying on OS file
+    /// events. Slower and heavier on large folders, but works on NFS/SMB
+    /// mounts where native events never arrive.
+    #[arg(long)]
+    poll_interval_ms: Option<u64>,
 
-                match e.name().as_ref() {
-                    b"stat" => {
-                        if let (Some(name_bytes), Some(val_bytes)) = (
-                            attr_map.get(b"name".as_ref()),
-                            attr_map.get(b"value".as_ref()),
-                        ) {
-                            let name = String::from_utf8_lossy(name_bytes).into_owned();
-                            let val = String::from_utf8_lossy(val_bytes).into_owned();
-                            if !BANNED_STATS.contains(&name.as_str()) {
-                                if let Some(player) = current_player.as_mut() {
-                                    player.stats.push((name, val));
-                                }
-                            }
-                        }
-                    }
-                    b"weapon" => {
-                        if let Some(name_bytes) = attr_map.get(b"name".as_ref()) {
-                            let weapon = Weapon {
-                                name: String::from_utf8_lossy(name_bytes).into_owned(),
-                                hits: attr_map
-                                    .get(b"hits".as_ref())
-                                    .map(|b| String::from_utf8_lossy(b).parse().unwrap_or(0))
-                                    .unwrap_or(0),
-                                shots: attr_map
-                                    .get(b"shots".as_ref())
-                                    .map(|b| String::from_utf8_lossy(b).parse().unwrap_or(0))
-                                    .unwrap_or(0),
-                                kills: attr_map
-                                    .get(b"kills".as_ref())
-                                    .map(|b| String::from_utf8_lossy(b).parse().unwrap_or(0))
-                                    .unwrap_or(0),
-                            };
-                            if let Some(player) = current_player.as_mut() {
-                                player.weapons.push(weapon);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            _ => (),
-        }
-        buf.clear();
-    }
+    /// Ignore further events for a file for this long after the first one,
+    /// as a single write can fire several. `0` turns this off.
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    debounce_ms: u64,
 
-    if game_match.map.is_empty() && game_match.teams.is_empty() {
-        bail!("no output generated from XML");
-    }
+    /// Only handle files with one of these extensions (also when gzipped,
+    /// e.g. `.xml.gz`). Anything else in the folder is ignored.
+    #[arg(long, value_delimiter = ',', default_value = "xml")]
+    extensions: Vec<String>,
 
-    Ok(game_match)
-}
+    /// How often to check a new file's size; it's read once the size holds
+    /// still for two checks in a row.
+    #[arg(long, value_name = "MS", default_value_t = 250)]
+    settle_poll_ms: u64,
 
-fn format_match_report(m: &Match) -> String {
-    let mut output = String::new();
-    output.push_str("*Match concluded*\n");
-    output.push_str(&format!(
-        "Map: {} \\| Type: {} \\| Duration: {}\n\n",
-        escape_markdown(&m.map),
-        escape_markdown(&m.match_type),
-        escape_markdown(&format_duration(&m.duration))
-    ));
+    /// Skip a file that is still growing after this long.
+    #[arg(long, value_name = "MS", default_value_t = 30_000)]
+    settle_max_wait_ms: u64,
 
-    for (i, team) in m.teams.iter().enumerate() {
-        if m.is_team_game {
-            let team_label = if i == 0 { "Team One" } else { "Team Two" };
-            output.push_str(&format!(
-                "*{}*: *{}*\n",
-                team_label,
-                escape_markdown(&team.score)
-            ));
-        }
+    /// Log an "alive" line this often, so a quiet bot can be told apart
+    /// from a stuck one.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_seconds: Option<u64>,
 
-        for player in &team.players {
-            output.push_str(&format!("```\nPlayer: {}\n", player.name));
-
-            for (stat_name, stat_val) in &player.stats {
-                output.push_str(&format!(
-                    "{}: {}\n",
-                    escape_markdown(stat_name),
-                    escape_markdown(stat_val)
-                ));
-            }
-
-            if !player.weapons.is_empty() {
-                output.push_str("Weapons: \n");
-                for w in &player.weapons {
-                    let accuracy = if w.hits >= w.shots && w.hits > 0 {
-                        100
-                    } else if w.shots > 0 {
-                        (w.hits * 100) / w.shots
-                    } else {
-                        0
-                    };
+    /// Report the files already in the folder at startup, e.g. matches
+    /// played while the bot was down.
+    #[arg(long)]
+    process_existing: bool,
 
-                    output.push_str(&format!(
-                        "{}: Shots: {} \\| Acc. {}% \\| Kills: {}\n",
-                        escape_markdown(&w.name),
-                        w.shots,
-                        accuracy,
-                        w.kills
-                    ));
-                }
-            }
-            output.push_str("```\n");
-        }
-    }
+    /// With --process-existing, skip files last modified more than this many
+    /// hours ago. Live events are always reported.
+    #[arg(long, requires = "process_existing")]
+    max_file_age_hours: Option<u64>,
 
-    output
-}
+    /// Report every match file in this folder, in name order, then exit.
+    /// Handy for demos and for load-testing delivery.
+    #[arg(long, value_name = "DIR", conflicts_with = "folder_path")]
+    replay: Option<PathBuf>,
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// With --replay, how long to wait between files. `0` sends them back
+    /// to back.
+    #[arg(long, default_value_t = 5, requires = "replay")]
+    replay_interval_seconds: u64,
 
-    #[test]
-    fn test_parse_content() {
-        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
-        let result = parse_content(xml).unwrap();
-        assert_eq!(result.map, "q3dm6");
-        assert_eq!(result.match_type, "TDM");
-        assert_eq!(result.duration, "601");
-        assert_eq!(result.teams.len(), 2);
+    /// Only list weapons the player actually got kills with.
+    #[arg(long)]
+    weapons_with_kills_only: bool,
 
-        // Team One (Score 5)
-        assert_eq!(result.teams[0].score, "5");
-        assert_eq!(result.teams[0].players.len(), 1);
+    /// Decimal separator used for ratios.
+    #[arg(long, default_value_t = '.')]
+    decimal_separator: char,
+
+    /// Digit-group separator for large integers (none by default).
+    #[arg(long)]
+    thousands_separator: Option<char>,
+
+    /// Digits after the decimal separator for ratios.
+    #[arg(long, default_value_t = 2)]
+    ratio_precision: usize,
+
+    /// Only report matches this player took part in (repeatable).
+    #[arg(long = "watch-player", value_name = "NAME")]
+    watch_players: Vec<String>,
+
+    /// Append a footer explaining the abbreviations used in the report.
+    #[arg(long)]
+    legend: bool,
+
+    /// Append each player's stats to `<dir>/<name>.jsonl` after every match.
+    #[arg(long)]
+    player_history_dir: Option<PathBuf>,
+
+    /// Also store every parsed match in this SQLite database, created if
+    /// missing, for queries over past games.
+    #[arg(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// JSON file keeping state (chat subscriptions) across restarts.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// File with live server info (player count, next map, ...) to put at
+    /// the top of every report. Read again for each report; may be missing.
+    #[arg(long, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+
+    /// Tag each report with the folder its file came from, e.g. `#duel-server`
+    /// for a file in `Duel Server/`, to tell several servers apart.
+    #[arg(long)]
+    folder_tags: bool,
+
+    /// Folder that `/preview <file>` may render files from; without it only
+    /// uploaded files can be previewed.
+    #[arg(long)]
+    preview_dir: Option<PathBuf>,
+
+    /// Star accuracies from fewer shots than this (10 if no value given).
+    #[arg(long, num_args = 0..=1, default_missing_value = "10")]
+    min_meaningful_shots: Option<u32>,
+
+    /// Call out FFA matches won by at most this many points (1 if no value
+    /// given).
+    #[arg(long, value_name = "MARGIN", num_args = 0..=1, default_missing_value = "1")]
+    photo_finish: Option<f64>,
+
+    /// Name the match MVP in the header: the top scorer, with ties going to
+    /// more kills and then fewer deaths.
+    #[arg(long)]
+    mvp: bool,
+
+    /// Only report matches this team played, with a W/L banner.
+    #[arg(long, value_name = "NAME")]
+    watch_team: Option<String>,
+
+    /// With --watch-team, only report the matches it won.
+    #[arg(long, requires = "watch_team")]
+    report_wins_only: bool,
+
+    /// Don't report matches that parsed without a single player, such as a
+    /// stats file that only has the header.
+    #[arg(long)]
+    skip_empty_matches: bool,
+
+    /// Skip matches whose best team or player score is below this (matches
+    /// without a numeric score are still sent).
+    #[arg(long)]
+    min_winning_score: Option<f64>,
+
+    /// Telegram user id allowed to run admin commands like /metrics (repeatable).
+    #[arg(long = "admin-id", value_name = "USER_ID")]
+    admin_ids: Vec<u64>,
+
+    /// Don't re-report the same map and players within this many seconds.
+    #[arg(long)]
+    same_match_throttle_seconds: Option<u64>,
+
+    /// Skip a report when it matches the last one sent: same map, players
+    /// and scores (for files that are rewritten while a match runs).
+    #[arg(long)]
+    only_on_change: bool,
+
+    /// Shorten a report to a `(rematch)` summary when the previous match had
+    /// the same map and players and was played within this many seconds.
+    #[arg(long)]
+    rematch_window_seconds: Option<u64>,
+
+    /// Group matches played less than this many seconds apart into one
+    /// session message, sent once no new match has arrived for that long.
+    #[arg(long)]
+    session_gap_seconds: Option<u64>,
+
+    /// Line put between the matches of a session message; empty for none.
+    #[arg(long, default_value = "━━━━━")]
+    match_divider: String,
+
+    /// Emoji shown before a team header by team color, e.g. `green=🟢`
+    /// (repeatable; red and blue are mapped by default).
+    #[arg(long = "team-emoji", value_name = "COLOR=EMOJI", value_parser = parse_key_val)]
+    team_emojis: Vec<(String, String)>,
+
+    /// Emoji leading the report header by match type, e.g. `CA=💥`
+    /// (repeatable; TDM, CTF, 1v1 and FFA are mapped by default).
+    #[arg(long = "type-emoji", value_name = "TYPE=EMOJI", value_parser = parse_key_val)]
+    type_emojis: Vec<(String, String)>,
+
+    /// Header emoji for match types without one; empty for none.
+    #[arg(long, default_value = "🎮")]
+    default_type_emoji: String,
+
+    /// Post reports into this forum topic instead of the chat's main thread.
+    /// Topic ids belong to one group, so with several chat ids every group
+    /// gets the same id; private chats never get one.
+    #[arg(long)]
+    message_thread_id: Option<i32>,
+
+    /// Attribute names tried, in order, for a stat's value before falling
+    /// back to the element text.
+    #[arg(long, value_delimiter = ',', default_value = "value,val,amount")]
+    stat_value_attrs: Vec<String>,
+
+    /// Stats tried, in order, for a teamless player's finishing position
+    /// when it has no "Score" stat.
+    #[arg(long, value_delimiter = ',', default_value = "Rank,Place")]
+    placement_stats: Vec<String>,
+
+    /// Stats to leave out, replacing the default list (item pickups and flags).
+    #[arg(long, value_delimiter = ',', value_name = "STATS")]
+    hide_stats: Option<Vec<String>>,
+
+    /// Stats to keep even though they are hidden by default, e.g. `RA,YA`.
+    #[arg(long, value_delimiter = ',', value_name = "STATS")]
+    show_stats: Vec<String>,
+
+    /// Which value to keep when a player has the same stat twice.
+    #[arg(long, value_enum, default_value_t)]
+    duplicate_stat_policy: DuplicateStatPolicy,
+
+    /// Report an old or alternate nickname under the player's usual name,
+    /// e.g. `oldnick=RealName` (repeatable; matched ignoring case).
+    #[arg(long = "alias", value_name = "NAME=CANONICAL", value_parser = parse_key_val)]
+    aliases: Vec<(String, String)>,
+
+    /// Element names read as a stat, for mods that don't use `<stat>`.
+    #[arg(long, value_delimiter = ',', default_value = "stat")]
+    stat_elements: Vec<String>,
+
+    /// Element names read as a weapon, e.g. `weapon,weaponStat,gun`.
+    #[arg(long, value_delimiter = ',', default_value = "weapon")]
+    weapon_elements: Vec<String>,
+
+    /// Mark weapons used accurately enough with a 🎯 badge.
+    #[arg(long)]
+    sharpshooter: bool,
+
+    /// Minimum accuracy for the sharpshooter badge on a weapon, e.g. `RL=40`
+    /// (repeatable; defaults to 50% for hitscan and 35% for projectile weapons).
+    #[arg(long = "sharpshooter-threshold", value_name = "WEAPON=PCT", value_parser = parse_weapon_threshold)]
+    sharpshooter_thresholds: Vec<(String, u32)>,
+
+    /// Show a title next to each player's name by their overall accuracy.
+    #[arg(long)]
+    accuracy_titles: bool,
+
+    /// Lowest accuracy for a title, e.g. `75=Railgod` (repeatable or
+    /// comma-separated; replaces the default Spray/Steady/Sharp/Deadeye).
+    #[arg(
+        long = "accuracy-bracket",
+        value_name = "PCT=TITLE",
+        value_delimiter = ',',
+        value_parser = parse_accuracy_bracket
+    )]
+    accuracy_brackets: Vec<(u32, String)>,
+
+    /// Hold reports back for this many seconds after startup (still parsing
+    /// and queueing them) so a misconfigured run can be stopped before it posts.
+    #[arg(long)]
+    startup_grace_seconds: Option<u64>,
+
+    /// List weapons in a fixed order (G, MG, SG, GL, RL, LG, RG, PG, BFG, then
+    /// anything else alphabetically) instead of the order the XML has them in.
+    #[arg(long)]
+    canonical_weapon_order: bool,
+
+    /// Leave a weapon out of the report, e.g. `G` or `mg` (repeatable).
+    #[arg(
+        long = "exclude-weapon",
+        visible_alias = "exclude-weapons",
+        value_name = "WEAPON"
+    )]
+    exclude_weapons: Vec<String>,
+
+    /// Stat holding each player's time on the server in seconds, shown as
+    /// `Played: 6m 12s` when present.
+    #[arg(long, default_value = "PlayTime")]
+    play_time_stat: String,
+
+    /// Show each player's share of their team's kills (of all kills in FFA).
+    #[arg(long)]
+    frag_share: bool,
+
+    /// Show each team's accuracy under its score, as the mean or median of
+    /// its players' (players without a shot are left out).
+    #[arg(long, value_enum, value_name = "STAT")]
+    team_accuracy_stat: Option<TeamAccuracyStat>,
+
+    /// Post only a short summary of each match (map, type, duration, scores).
+    #[arg(long)]
+    compact: bool,
+
+    /// Lay out each player's stats in two columns to save vertical space.
+    #[arg(long)]
+    two_column_stats: bool,
+
+    /// Add a block with each weapon's kills and accuracy across the match.
+    #[arg(long)]
+    weapon_meta: bool,
+
+    /// Cut player names longer than this short with `…` in reports.
+    #[arg(long, value_name = "CHARS")]
+    max_name_length: Option<usize>,
+
+    /// Leave clan tags off player names in reports (`KDZ:VaNeZzz` shows as
+    /// `VaNeZzz`). History files and the database keep the full name.
+    #[arg(long)]
+    strip_clan_tags: bool,
+
+    /// Post a short summary and attach the full report to it as a `.txt` reply.
+    #[arg(long, conflicts_with = "kv")]
+    summary_with_document: bool,
+
+    /// In team games, post the header and each team's block as separate
+    /// messages instead of one long report.
+    #[arg(long)]
+    message_per_team: bool,
+
+    /// Hide stats that tell nothing apart in a match: the same value for
+    /// every player, or zero for everyone.
+    #[arg(long)]
+    auto_suppress_uniform: bool,
+
+    /// Emit machine-parseable `key=value` lines instead of the formatted report.
+    #[arg(long)]
+    kv: bool,
+
+    /// How reports are marked up; `plain` suits bridges that mangle
+    /// Telegram's MarkdownV2.
+    #[arg(long, value_enum, default_value_t, conflicts_with = "kv")]
+    format: ReportFormat,
+
+    /// IANA time zone for the time a match was played, e.g. `Europe/Berlin`.
+    /// UTC by default.
+    #[arg(long, value_name = "ZONE")]
+    timezone: Option<Tz>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+enum DuplicateStatPolicy {
+    First,
+    #[default]
+    Last,
+    /// Add the values up, falling back to the last one if any isn't a number.
+    Sum,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+enum ReportFormat {
+    /// Bold headings and code blocks, sent as MarkdownV2.
+    #[default]
+    Markdown,
+    /// The same report without any markup, sent without a parse mode.
+    Plain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum TeamAccuracyStat {
+    Mean,
+    /// The middle player's accuracy, so one outlier doesn't drag it around.
+    Median,
+}
+
+/// The bot for `token` (or `TELOXIDE_TOKEN`), on `api_url` when given.
+fn build_bot(token: Option<String>, api_url: Option<Url>) -> Bot {
+    let bot = match token {
+        Some(token) => Bot::new(token),
+        None => Bot::from_env(),
+    };
+    match api_url {
+        Some(url) => {
+            log::info!("Using Bot API server at {}", url);
+            bot.set_api_url(url)
+        }
+        None => bot,
+    }
+}
+
+/// An http(s) base URL, with a trailing slash added so teloxide appends the
+/// `bot<token>/<method>` path to it rather than replacing its last segment.
+fn parse_api_url(s: &str) -> Result<Url, String> {
+    let mut url = Url::parse(s).map_err(|e| format!("invalid URL '{}': {}", s, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+        return Err(format!("expected an http(s) URL, got '{}'", s));
+    }
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", s))
+}
+
+fn parse_weapon_threshold(s: &str) -> Result<(String, u32), String> {
+    let (weapon, pct) = parse_key_val(s)?;
+    let pct = pct
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|e| format!("invalid accuracy '{}': {}", pct, e))?;
+    Ok((weapon.to_uppercase(), pct))
+}
+
+/// Hitscan weapons are easier to land, so they need a higher accuracy than
+/// projectile ones for the sharpshooter badge.
+fn default_sharpshooter_thresholds() -> HashMap<String, u32> {
+    let hitscan = ["MG", "SG", "LG", "RG"].map(|w| (w.to_string(), 50));
+    let projectile = ["GL", "RL", "PG", "BFG"].map(|w| (w.to_string(), 35));
+    hitscan.into_iter().chain(projectile).collect()
+}
+
+fn parse_accuracy_bracket(s: &str) -> Result<(u32, String), String> {
+    let (pct, title) = parse_key_val(s)?;
+    let pct = pct
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|e| format!("invalid accuracy '{}': {}", pct, e))?;
+    Ok((pct, title))
+}
+
+fn default_accuracy_brackets() -> Vec<(u32, String)> {
+    [(0, "Spray"), (20, "Steady"), (40, "Sharp"), (60, "Deadeye")]
+        .map(|(min, title)| (min, title.to_string()))
+        .to_vec()
+}
+
+/// The title of the highest bracket `pct` reaches; anything under the
+/// lowest bracket still gets its title. `brackets` is sorted lowest first.
+fn accuracy_title(pct: u32, brackets: &[(u32, String)]) -> &str {
+    brackets
+        .iter()
+        .rev()
+        .find(|(min, _)| pct >= *min)
+        .or(brackets.first())
+        .map_or("", |(_, title)| title.as_str())
+}
+
+fn default_team_emojis() -> HashMap<String, String> {
+    HashMap::from([
+        ("red".to_string(), "🔴".to_string()),
+        ("blue".to_string(), "🔵".to_string()),
+    ])
+}
+
+fn default_type_emojis() -> HashMap<String, String> {
+    HashMap::from([
+        ("TDM".to_string(), "⚔️".to_string()),
+        ("CTF".to_string(), "🏁".to_string()),
+        ("1V1".to_string(), "🎯".to_string()),
+        ("FFA".to_string(), "🌀".to_string()),
+    ])
+}
+
+/// Decides whether a parsed match is worth sending at all.
+#[derive(Debug, Default, Clone)]
+struct MatchFilter {
+    watch_players: Vec<String>,
+    watch_team: Option<String>,
+    wins_only: bool,
+    min_winning_score: Option<f64>,
+    skip_empty: bool,
+}
+
+impl MatchFilter {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            watch_players: args
+                .watch_players
+                .iter()
+                .map(|n| normalize_name(n))
+                .collect(),
+            watch_team: args.watch_team.clone(),
+            wins_only: args.report_wins_only,
+            min_winning_score: args.min_winning_score,
+            skip_empty: args.skip_empty_matches,
+        }
+    }
+
+    /// Returns why the match should not be reported, or `None` to send it.
+    fn skip_reason(&self, m: &Match) -> Option<String> {
+        if self.skip_empty && m.teams.iter().all(|t| t.players.is_empty()) {
+            return Some("no players in the match".to_string());
+        }
+        if !self.watch_players.is_empty() {
+            let watched_present = m
+                .teams
+                .iter()
+                .flat_map(|t| &t.players)
+                .any(|p| self.watch_players.contains(&normalize_name(&p.name)));
+            if !watched_present {
+                return Some("no watched player took part".to_string());
+            }
+        }
+        if let Some(team) = &self.watch_team {
+            let Some(idx) = m.find_team(team) else {
+                return Some(format!("team {} did not play", team));
+            };
+            if self.wins_only && m.winning_team() != Some(idx) {
+                return Some(format!("team {} did not win", team));
+            }
+        }
+        if let (Some(min), Some(top)) = (self.min_winning_score, m.top_score()) {
+            if top < min {
+                return Some(format!("top score {} is below {}", top, min));
+            }
+        }
+        None
+    }
+}
+
+/// Case- and whitespace-insensitive form of a player name, for comparisons.
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Splits a clan tag off the front of `name`, giving the tag and the rest:
+/// `KDZ:VaNeZzz` or `KDZ|VaNeZzz`, and bracketed ones like `[KDZ]VaNeZzz`.
+/// Tags are short and without spaces, and something has to be left after
+/// them, so ordinary names come back as `None`.
+fn clan_tag(name: &str) -> Option<(&str, &str)> {
+    const MAX_TAG_CHARS: usize = 6;
+    let (tag, rest) = match name.chars().next()? {
+        open @ ('[' | '(' | '{' | '<') => {
+            let close = match open {
+                '[' => ']',
+                '(' => ')',
+                '{' => '}',
+                _ => '>',
+            };
+            name[1..].split_once(close)?
+        }
+        _ => name.split_once([':', '|'])?,
+    };
+    let rest = rest.trim_start_matches([':', '|', ' ']);
+    let valid = !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_CHARS
+        && !tag.contains(char::is_whitespace)
+        && !rest.is_empty();
+    valid.then_some((tag, rest))
+}
+
+/// The name `name` is reported under: its `--alias` target, or itself.
+/// `aliases` is keyed by `normalize_name`.
+fn canonicalize_name(name: &str, aliases: &HashMap<String, String>) -> String {
+    aliases
+        .get(&normalize_name(name))
+        .cloned()
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Number formatting shared by every stat in the report, so precision and
+/// separators stay consistent no matter which feature prints the value.
+#[derive(Debug, Clone)]
+struct StatFormatter {
+    decimal_separator: char,
+    thousands_separator: Option<char>,
+    ratio_precision: usize,
+}
+
+impl Default for StatFormatter {
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            thousands_separator: None,
+            ratio_precision: 2,
+        }
+    }
+}
+
+impl StatFormatter {
+    fn ratio(&self, value: f32) -> String {
+        let formatted = format!("{:.*}", self.ratio_precision, value);
+        match formatted.split_once('.') {
+            Some((whole, frac)) => format!("{}{}{}", whole, self.decimal_separator, frac),
+            None => formatted,
+        }
+    }
+
+    fn percent(&self, value: u32) -> String {
+        format!("{}%", self.integer(value.into()))
+    }
+
+    fn integer(&self, value: u64) -> String {
+        let digits = value.to_string();
+        let Some(sep) = self.thousands_separator else {
+            return digits;
+        };
+
+        let mut grouped = String::new();
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                grouped.push(sep);
+            }
+            grouped.push(c);
+        }
+        grouped
+    }
+}
+
+/// Rendering choices for `format_match_report`, resolved once from the CLI.
+#[derive(Debug, Default, Clone)]
+struct ReportOptions {
+    weapons_with_kills_only: bool,
+    legend: bool,
+    min_meaningful_shots: Option<u32>,
+    photo_finish: Option<f64>,
+    mvp: bool,
+    watch_team: Option<String>,
+    /// Lowercased team color -> emoji.
+    team_emojis: HashMap<String, String>,
+    /// Uppercased match type -> header emoji.
+    type_emojis: HashMap<String, String>,
+    default_type_emoji: Option<String>,
+    /// Uppercased weapon -> minimum accuracy for the badge; `None` disables it.
+    sharpshooter: Option<HashMap<String, u32>>,
+    /// `--accuracy-titles` brackets, lowest first.
+    accuracy_titles: Option<Vec<(u32, String)>>,
+    canonical_weapon_order: bool,
+    /// Uppercased weapon names hidden from player blocks.
+    excluded_weapons: HashSet<String>,
+    play_time_stat: String,
+    frag_share: bool,
+    team_accuracy_stat: Option<TeamAccuracyStat>,
+    compact: bool,
+    two_column_stats: bool,
+    weapon_meta: bool,
+    max_name_length: Option<usize>,
+    strip_clan_tags: bool,
+    summary_with_document: bool,
+    message_per_team: bool,
+    /// Between the matches of a combined session message.
+    match_divider: String,
+    auto_suppress_uniform: bool,
+    /// Stat names left out of player blocks; filled per match.
+    hidden_stats: HashSet<String>,
+    kv: bool,
+    format: ReportFormat,
+    timezone: Option<Tz>,
+    formatter: StatFormatter,
+}
+
+/// Report features `/set` can switch while the bot runs.
+const RUNTIME_FEATURES: [&str; 6] = [
+    "compact",
+    "legend",
+    "frag-share",
+    "weapon-order",
+    "suppress-uniform",
+    "kills-only",
+];
+
+impl ReportOptions {
+    /// The switch behind a `RUNTIME_FEATURES` name.
+    fn feature(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "compact" => Some(&mut self.compact),
+            "legend" => Some(&mut self.legend),
+            "frag-share" => Some(&mut self.frag_share),
+            "weapon-order" => Some(&mut self.canonical_weapon_order),
+            "suppress-uniform" => Some(&mut self.auto_suppress_uniform),
+            "kills-only" => Some(&mut self.weapons_with_kills_only),
+            _ => None,
+        }
+    }
+
+    /// A player name as reports show it, without its clan tag if asked and
+    /// shortened to `max_name_length`.
+    fn display_name(&self, name: &str) -> String {
+        let name = match clan_tag(name) {
+            Some((_, rest)) if self.strip_clan_tags => rest,
+            _ => name,
+        };
+        match self.max_name_length {
+            Some(max) => truncate(name, max),
+            None => name.to_string(),
+        }
+    }
+
+    /// Telegram parse mode matching the renderer these options select.
+    fn parse_mode(&self) -> Option<ParseMode> {
+        if self.kv || self.format == ReportFormat::Plain {
+            None
+        } else {
+            Some(ParseMode::MarkdownV2)
+        }
+    }
+
+    fn from_args(args: &Args) -> Self {
+        Self {
+            weapons_with_kills_only: args.weapons_with_kills_only,
+            legend: args.legend,
+            min_meaningful_shots: args.min_meaningful_shots,
+            photo_finish: args.photo_finish,
+            mvp: args.mvp,
+            watch_team: args.watch_team.clone(),
+            team_emojis: default_team_emojis()
+                .into_iter()
+                .chain(
+                    args.team_emojis
+                        .iter()
+                        .map(|(color, emoji)| (color.to_lowercase(), emoji.clone())),
+                )
+                .collect(),
+            type_emojis: default_type_emojis()
+                .into_iter()
+                .chain(
+                    args.type_emojis
+                        .iter()
+                        .map(|(kind, emoji)| (kind.to_uppercase(), emoji.clone())),
+                )
+                .collect(),
+            default_type_emoji: Some(args.default_type_emoji.clone()).filter(|e| !e.is_empty()),
+            sharpshooter: args.sharpshooter.then(|| {
+                let mut thresholds = default_sharpshooter_thresholds();
+                thresholds.extend(args.sharpshooter_thresholds.iter().cloned());
+                thresholds
+            }),
+            accuracy_titles: args.accuracy_titles.then(|| {
+                let mut brackets = if args.accuracy_brackets.is_empty() {
+                    default_accuracy_brackets()
+                } else {
+                    args.accuracy_brackets.clone()
+                };
+                brackets.sort_by_key(|(min, _)| *min);
+                brackets
+            }),
+            canonical_weapon_order: args.canonical_weapon_order,
+            excluded_weapons: args
+                .exclude_weapons
+                .iter()
+                .map(|w| w.to_uppercase())
+                .collect(),
+            play_time_stat: args.play_time_stat.clone(),
+            frag_share: args.frag_share,
+            team_accuracy_stat: args.team_accuracy_stat,
+            compact: args.compact,
+            two_column_stats: args.two_column_stats,
+            weapon_meta: args.weapon_meta,
+            max_name_length: args.max_name_length,
+            strip_clan_tags: args.strip_clan_tags,
+            summary_with_document: args.summary_with_document,
+            message_per_team: args.message_per_team,
+            match_divider: args.match_divider.clone(),
+            auto_suppress_uniform: args.auto_suppress_uniform,
+            hidden_stats: HashSet::new(),
+            kv: args.kv,
+            format: args.format,
+            timezone: args.timezone,
+            formatter: StatFormatter {
+                decimal_separator: args.decimal_separator,
+                thousands_separator: args.thousands_separator,
+                ratio_precision: args.ratio_precision,
+            },
+        }
+    }
+}
+
+fn escape_markdown(message: &str) -> String {
+    let mut escaped_message = String::new();
+    for c in message.chars() {
+        match c {
+            '*' | '_' | '[' | ']' | '(' | ')' | '~' | '>' | '#' | '+' | '-' | '=' | '|' | '{'
+            | '}' | '.' | '!' => {
+                escaped_message.push('\\');
+            }
+            _ => {}
+        }
+        escaped_message.push(c);
+    }
+    escaped_message
+}
+
+/// Parses a numeric attribute leniently: some mods emit floats (`452.7`)
+/// where integers are expected.
+fn parse_number(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// Parses a whole count, truncating fractions and clamping negatives to 0.
+fn parse_count(value: &str) -> u32 {
+    parse_number(value).map_or(0, |v| v as u32)
+}
+
+fn duration_secs(duration: &str) -> Option<u64> {
+    parse_number(duration)
+        .filter(|v| *v >= 0.0)
+        .map(|v| v as u64)
+}
+
+/// Accepts epoch seconds or ISO 8601 (with or without an offset; naive
+/// times are taken as UTC).
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Some(secs) = parse_number(value) {
+        return DateTime::from_timestamp(secs as i64, 0);
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+fn format_duration(duration: &str) -> String {
+    let Some(total_seconds) = duration_secs(duration) else {
+        return duration.to_string();
+    };
+
+    let minutes = total_seconds / 60;
+    let seconds = total_seconds % 60;
+    format!("{minutes}:{seconds:02}")
+}
+
+fn humanize_duration(duration: &str) -> String {
+    let Some(total_seconds) = duration_secs(duration) else {
+        return duration.to_string();
+    };
+
+    let minutes = total_seconds / 60;
+    let seconds = total_seconds % 60;
+    if minutes == 0 {
+        format!("{seconds}s")
+    } else {
+        format!("{minutes}m {seconds}s")
+    }
+}
+
+/// Removes Quake 3 color codes (`^` followed by a digit) from a name. Any
+/// other caret is kept as is.
+fn strip_color_codes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '^' && chars.peek().is_some_and(|n| n.is_ascii_digit()) {
+            chars.next();
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Cuts `s` to at most `max` characters, the last of them `…` when anything
+/// was dropped.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut cut: String = s.chars().take(max.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = Args::parse();
+    let report_opts = ReportOptions::from_args(&args);
+    let parse_opts = ParseOptions::from_args(&args);
+    let filter = MatchFilter::from_args(&args);
+    if let Some(test_file) = args.test_file {
+        let data = tokio::fs::read_to_string(&test_file).await?;
+        for match_data in parse_all_matches(data, &parse_opts)? {
+            let msg = render_report(&match_data, &report_opts);
+            for part in msg.parts() {
+                println!("{}", part);
+            }
+            if let Some(doc) = msg.document {
+                println!("--- {} attached ---\n{}", doc.file_name, doc.contents);
+            }
+        }
+        return Ok(());
+    }
+
+    dotenvy::dotenv().ok();
+    pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    log::info!("Starting q3reportbot...");
+
+    let mut token = None;
+    if let Some(path) = &args.config_json {
+        let config = config::Config::load(path)?;
+        log::info!("Loaded {:?} from {:?}", config, path);
+        token = config.apply(&mut args);
+    }
+
+    let folder_path = match &args.replay {
+        Some(dir) => dir.display().to_string(),
+        None => args.folder_path.clone().ok_or_else(|| {
+            anyhow::anyhow!("--folder-path is required unless --test-file is used")
+        })?,
+    };
+    let mut chat_ids = Vec::new();
+    for chat_id_arg in &args.chat_ids {
+        let chat_id_val = chat_id_arg
+            .trim()
+            .parse::<i64>()
+            .map_err(|e| anyhow::anyhow!("Failed to parse chat_id '{}': {}", chat_id_arg, e))?;
+        log::info!("Target chat ID: {}", chat_id_arg);
+        chat_ids.push(ChatId(chat_id_val));
+    }
+    if chat_ids.is_empty() && args.dry_run {
+        // a stand-in recipient, so there is something to print for
+        chat_ids.push(ChatId(0));
+    }
+    if chat_ids.is_empty() && args.state_file.is_none() {
+        bail!(
+            "--chat-id is required unless --test-file is used or chats /subscribe via --state-file"
+        );
+    }
+    let (store, db) = open_storage(&args)?;
+    let store = Arc::new(store);
+    let metrics = Arc::new(Metrics::default());
+    let admins = commands::Admins(args.admin_ids.iter().map(|&id| UserId(id)).collect());
+    let report_chats = commands::ReportChats(chat_ids.clone());
+    let preview = commands::Preview {
+        parse_opts: parse_opts.clone(),
+        local_dir: args.preview_dir.clone(),
+    };
+    // shared with /set, which switches features while we run
+    let report_opts = Arc::new(Mutex::new(report_opts));
+    let bot = (!args.dry_run).then(|| build_bot(token, args.api_url.clone()));
+    let sink = match &bot {
+        Some(bot) => Sink::Telegram(bot.clone()),
+        None => {
+            log::info!("Dry run: printing reports instead of sending them");
+            Sink::Stdout
+        }
+    };
+
+    log::info!("Monitoring folder: {}", folder_path);
+    store.read(|s| {
+        if !s.subscribed_chats.is_empty() {
+            log::info!("Subscribed chats: {:?}", s.subscribed_chats);
+        }
+    });
+
+    let mut reporter = Reporter {
+        sink,
+        chat_ids,
+        parse_opts,
+        report_opts: report_opts.clone(),
+        filter,
+        history_dir: args.player_history_dir,
+        db,
+        status_file: args.status_file,
+        folder_tags: args.folder_tags,
+        store: store.clone(),
+        metrics: metrics.clone(),
+        throttle: args
+            .same_match_throttle_seconds
+            .map(|secs| MatchThrottle::new(Duration::from_secs(secs))),
+        last_sent: args.only_on_change.then(LastSent::default),
+        rematches: args
+            .rematch_window_seconds
+            .map(|secs| RematchDetector::new(chrono::Duration::seconds(secs as i64))),
+        sessions: args
+            .session_gap_seconds
+            .map(|secs| SessionGroups::new(Duration::from_secs(secs))),
+        settle: Settle {
+            poll: Duration::from_millis(args.settle_poll_ms),
+            max_wait: Duration::from_millis(args.settle_max_wait_ms),
+        },
+        unreadable: HashSet::new(),
+        thread_id: args.message_thread_id.map(|id| ThreadId(MessageId(id))),
+        grace: args.startup_grace_seconds.map(|secs| {
+            log::info!("Holding reports for {}s after startup", secs);
+            StartupGrace::new(Instant::now() + Duration::from_secs(secs))
+        }),
+    };
+    let startup_scan = args.process_existing.then(|| StartupScan {
+        max_age: args
+            .max_file_age_hours
+            .map(|hours| Duration::from_secs(hours * 3600)),
+    });
+    if let Some(secs) = args.heartbeat_seconds {
+        tokio::spawn(heartbeat(
+            metrics.clone(),
+            PathBuf::from(&folder_path),
+            Duration::from_secs(secs),
+            |line| log::info!("{}", line),
+        ));
+    }
+    if let Some(dir) = &args.replay {
+        let interval = Duration::from_secs(args.replay_interval_seconds);
+        let count = replay(&mut reporter, dir, interval, &args.extensions).await?;
+        log::info!("Replayed {} file(s) from {:?}", count, dir);
+        return Ok(());
+    }
+    let monitor = monitor_folder(
+        reporter,
+        folder_path,
+        args.poll_interval_ms,
+        Duration::from_millis(args.debounce_ms),
+        args.extensions,
+        startup_scan,
+        shutdown_signal(),
+    );
+    let Some(bot) = bot else {
+        // nobody to take commands from without a bot
+        monitor.await?;
+        return Ok(());
+    };
+    tokio::select! {
+        res = monitor => res?,
+        _ = commands::run_dispatcher(
+            bot,
+            store,
+            metrics,
+            admins,
+            report_chats,
+            preview,
+            report_opts,
+        ) => {},
+    }
+
+    Ok(())
+}
+
+/// The state file and database, or an in-memory state and no database for
+/// `--dry-run`, which must not mark files processed or store matches that a
+/// real run would then skip.
+fn open_storage(args: &Args) -> Result<(StateStore, Option<MatchDb>)> {
+    if args.dry_run {
+        if args.state_file.is_some() || args.db.is_some() {
+            log::info!("Dry run: not writing to the state file or database");
+        }
+        return Ok((StateStore::default(), None));
+    }
+    let store = StateStore::open(args.state_file.clone())?;
+    let db = args.db.as_deref().map(MatchDb::open).transpose()?;
+    Ok((store, db))
+}
+
+fn create_watcher<F: EventHandler>(
+    handler: F,
+    poll_interval_ms: Option<u64>,
+) -> notify::Result<Box<dyn Watcher>> {
+    match poll_interval_ms {
+        Some(ms) => {
+            let config = notify::Config::default().with_poll_interval(Duration::from_millis(ms));
+            Ok(Box::new(PollWatcher::new(handler, config)?))
+        }
+        None => Ok(Box::new(recommended_watcher(handler)?)),
+    }
+}
+
+async fn monitor_folder(
+    mut reporter: Reporter,
+    folder_path: String,
+    poll_interval_ms: Option<u64>,
+    debounce: Duration,
+    extensions: Vec<String>,
+    startup_scan: Option<StartupScan>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), BotError> {
+    // forward into an async channel so waiting for events doesn't block the
+    // runtime the command dispatcher shares with us
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = create_watcher(
+        move |event| {
+            let _ = tx.send(event);
+        },
+        poll_interval_ms,
+    )?;
+    let path = Path::new(&folder_path);
+
+    if let Some(ms) = poll_interval_ms {
+        log::info!("Using polling watcher every {}ms", ms);
+    }
+
+    watch_folder(watcher.as_mut(), path)?;
+
+    log::info!("Watching for changes in {:?}", path);
+
+    // files handled in this or an earlier run, by mtime; the watcher is
+    // already running, so one written during the startup scan shows up twice
+    if let Err(e) = reporter.store.update(State::forget_missing_files) {
+        log::error!("Failed to prune processed files: {}", e);
+    }
+    let mut processed = ProcessedFiles::load(&reporter.store);
+    let mut debounce = EventDebounce::new(debounce);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+    if let Some(scan) = startup_scan {
+        match startup_files(
+            path,
+            &processed.files,
+            &extensions,
+            scan.max_age,
+            SystemTime::now(),
+        ) {
+            Ok(files) => {
+                log::info!("Startup scan found {} file(s) to report", files.len());
+                for fpath in files {
+                    report_once(&mut reporter, &mut processed, fpath).await;
+                }
+                processed.save_released(&reporter);
+            }
+            Err(e) => log::error!("Startup scan of {:?} failed: {}", path, e),
+        }
+    }
+
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        tokio::select! {
+            // only polled between files, so one being handled is finished first
+            () = &mut shutdown => {
+                log::info!("Shutting down");
+                break;
+            }
+            event = rx.recv() => {
+                let Some(event) = event else {
+                    log::error!("Watcher channel closed");
+                    break;
+                };
+                match event {
+                    Ok(e) => {
+                        for fpath in files_for_event(&e, path, &processed.files) {
+                            if !is_match_file(&fpath, &extensions) {
+                                log::debug!("Ignoring {:?}: not a match file", fpath);
+                                continue;
+                            }
+                            if !debounce.allow(&fpath, Instant::now()) {
+                                log::debug!("Ignoring repeated event for {:?}", fpath);
+                                continue;
+                            }
+                            log::info!("New file detected: {:?}", fpath);
+                            report_once(&mut reporter, &mut processed, fpath).await;
+                        }
+                        processed.save_released(&reporter);
+                    }
+                    Err(e) => log::error!("Watcher error: {:?}", e),
+                }
+            }
+            _ = tick.tick(), if reporter.has_timers() => {
+                reporter.tick().await;
+                processed.save_released(&reporter);
+            }
+        }
+    }
+    // no more events while the held reports go out and the database
+    // closes; the state file is already saved after every change
+    drop(watcher);
+    reporter.flush().await;
+    processed.save_released(&reporter);
+    if let Some(db) = reporter.db.take() {
+        if let Err(e) = db.close() {
+            log::error!("Failed to close the database: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM (what systemd sends) on Unix. A signal
+/// that can't be listened for is logged and never fires.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Unable to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                log::error!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// `--replay`: runs every match file under `dir` through the reporter, one
+/// every `interval` (or straight after each other for zero) and in name
+/// order, with the same filters as live mode. Reports still held for a
+/// session or the startup grace are sent at the end. Returns how many files
+/// there were.
+async fn replay(
+    reporter: &mut Reporter,
+    dir: &Path,
+    interval: Duration,
+    extensions: &[String],
+) -> Result<usize, BotError> {
+    let files = startup_files(dir, &HashMap::new(), extensions, None, SystemTime::now())?;
+    log::info!(
+        "Replaying {} file(s), one every {:?}",
+        files.len(),
+        interval
+    );
+    let mut ticks = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+    for fpath in &files {
+        if let Some(ticks) = ticks.as_mut() {
+            ticks.tick().await;
+        }
+        reporter.process_file(fpath).await;
+    }
+    reporter.flush().await;
+    Ok(files.len())
+}
+
+/// Hands `emit` an "alive" line every `period`, starting one period in.
+async fn heartbeat(
+    metrics: Arc<Metrics>,
+    folder: PathBuf,
+    period: Duration,
+    mut emit: impl FnMut(String),
+) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        emit(format!(
+            "alive, {} files processed, watching {}",
+            metrics.files_processed(),
+            folder.display()
+        ));
+    }
+}
+
+/// Match files handled so far, by the mtime they had then, so a rewritten
+/// file counts as new again.
+struct ProcessedFiles {
+    files: HashMap<PathBuf, SystemTime>,
+    /// Handled, but with reports still held for a session or the startup
+    /// grace; only saved to the state once those are sent.
+    held: Vec<(PathBuf, SystemTime)>,
+}
+
+impl ProcessedFiles {
+    fn load(store: &StateStore) -> Self {
+        Self {
+            files: store.read(|s| s.processed_files.clone().into_iter().collect()),
+            held: Vec::new(),
+        }
+    }
+
+    /// Saves the held files once the reporter has nothing left to send.
+    fn save_released(&mut self, reporter: &Reporter) {
+        if self.held.is_empty() || reporter.holds_reports() {
+            return;
+        }
+        for (fpath, mtime) in std::mem::take(&mut self.held) {
+            save_processed(&reporter.store, fpath, mtime);
+        }
+    }
+}
+
+fn save_processed(store: &StateStore, fpath: PathBuf, mtime: SystemTime) {
+    if let Err(e) = store.update(|s| s.mark_processed(fpath, mtime)) {
+        log::error!("Failed to record processed file: {}", e);
+    }
+}
+
+/// Processes `fpath` unless this version of it was handled before, which
+/// notify's repeated events and restarts would otherwise cause. Once its
+/// reports are out, or it was skipped for good, it is recorded in the state
+/// so the next run skips it too; one that couldn't be read or parsed is left
+/// for a later write to fix.
+async fn report_once(reporter: &mut Reporter, processed: &mut ProcessedFiles, fpath: PathBuf) {
+    if is_processed(&processed.files, &fpath) {
+        log::debug!("{:?} was already processed, skipping", fpath);
+        return;
+    }
+    if !settle_file(&fpath, reporter.settle).await {
+        return;
+    }
+    // taken once the writes are done but before reading, so a write while
+    // we read makes the file new again
+    let Some(mtime) = modified_time(&fpath) else {
+        log::debug!("{:?} is gone, skipping", fpath);
+        return;
+    };
+    match reporter.process_settled(&fpath).await {
+        FileOutcome::Unread => {}
+        FileOutcome::Held => {
+            processed.files.insert(fpath.clone(), mtime);
+            processed.held.push((fpath, mtime));
+        }
+        FileOutcome::Done => {
+            processed.files.insert(fpath.clone(), mtime);
+            save_processed(&reporter.store, fpath, mtime);
+        }
+    }
+}
+
+/// Whether `path` was processed and hasn't been modified since.
+fn is_processed(processed: &HashMap<PathBuf, SystemTime>, path: &Path) -> bool {
+    processed
+        .get(path)
+        .is_some_and(|&mtime| modified_time(path) == Some(mtime))
+}
+
+fn watch_folder(watcher: &mut dyn Watcher, path: &Path) -> Result<(), BotError> {
+    // a folder we can't read would otherwise fail on every event, forever
+    std::fs::read_dir(path).map_err(|e| watch_setup_error(path, e.into()))?;
+    watcher
+        .watch(path, notify::RecursiveMode::Recursive)
+        .map_err(|e| watch_setup_error(path, e))
+}
+
+fn is_permission_denied(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::PermissionDenied
+}
+
+/// Explains a failure to start watching `path`; a permission problem needs
+/// the operator to step in.
+fn watch_setup_error(path: &Path, err: notify::Error) -> BotError {
+    match &err.kind {
+        notify::ErrorKind::Io(e) if is_permission_denied(e) => BotError::Config(format!(
+            "Permission denied reading {:?}: run the bot as a user that can read it, \
+             or grant access with e.g. `chmod -R o+rX {}`",
+            path,
+            path.display()
+        )),
+        _ => BotError::Watch(err.add_path(path.to_path_buf())),
+    }
+}
+
+/// Works out which files a watcher event asks us to handle: new files, and
+/// ones written or renamed into place since they were last processed. An
+/// overflow (rescan) event means notify dropped events, so every file under
+/// `root` that hasn't been processed yet is picked up again. Events about
+/// `root` itself (metadata changes, or it being removed and recreated) are
+/// dropped.
+fn files_for_event(
+    event: &notify::Event,
+    root: &Path,
+    processed: &HashMap<PathBuf, SystemTime>,
+) -> Vec<PathBuf> {
+    if event.need_rescan() {
+        log::warn!("Watcher event buffer overflowed, rescanning {:?}", root);
+        return match unprocessed_files(root, processed) {
+            Ok(files) => {
+                log::info!("Recovery scan found {} unprocessed file(s)", files.len());
+                files
+            }
+            Err(e) => {
+                log::error!("Recovery scan of {:?} failed: {}", root, e);
+                Vec::new()
+            }
+        };
+    }
+
+    if !event.paths.is_empty() && event.paths.iter().all(|p| p == root) {
+        log::trace!("Ignoring {:?} event for the watched folder", event.kind);
+        return Vec::new();
+    }
+
+    match (&event.kind, event.paths.last()) {
+        (EventKind::Create(_), Some(fpath)) if !fpath.is_dir() => vec![fpath.clone()],
+        // rewritten in place, or a temp file renamed over it; the last path
+        // of a rename is where it ended up
+        (
+            EventKind::Modify(
+                ModifyKind::Data(_)
+                | ModifyKind::Any
+                | ModifyKind::Name(RenameMode::To | RenameMode::Both | RenameMode::Any),
+            ),
+            Some(fpath),
+        ) if fpath.is_file() && !is_processed(processed, fpath) => vec![fpath.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// Reporting the files that were already there when the bot started.
+struct StartupScan {
+    /// Skip files last modified longer ago than this.
+    max_age: Option<Duration>,
+}
+
+/// Unprocessed match files (see `is_match_file`) under `root` for the startup
+/// scan, leaving out those older than `max_age` at `now`. A file whose mtime
+/// can't be read is kept.
+fn startup_files(
+    root: &Path,
+    processed: &HashMap<PathBuf, SystemTime>,
+    extensions: &[String],
+    max_age: Option<Duration>,
+    now: SystemTime,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = unprocessed_files(root, processed)?;
+    files.retain(|f| is_match_file(f, extensions));
+    if let Some(max_age) = max_age {
+        files.retain(|f| {
+            let age = modified_time(f).and_then(|mtime| now.duration_since(mtime).ok());
+            match age {
+                Some(age) if age > max_age => {
+                    log::info!(
+                        "Skipping {:?}, last modified {}h ago",
+                        f,
+                        age.as_secs() / 3600
+                    );
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    Ok(files)
+}
+
+/// Whether `path` has one of `extensions`, ignoring case and looking past a
+/// trailing `.gz`. A file without an extension (`match`, or `match.gz`) never
+/// matches.
+fn is_match_file(path: &Path, extensions: &[String]) -> bool {
+    let ext = match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("gz") => path
+            .file_stem()
+            .and_then(|stem| Path::new(stem).extension()),
+        ext => ext,
+    };
+    let Some(ext) = ext else {
+        return false;
+    };
+    extensions
+        .iter()
+        .any(|allowed| ext.eq_ignore_ascii_case(allowed.trim_start_matches('.')))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn unprocessed_files(
+    root: &Path,
+    processed: &HashMap<PathBuf, SystemTime>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() {
+                dirs.push(entry_path);
+            } else if !is_processed(processed, &entry_path) {
+                files.push(entry_path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// One line of a player's history file.
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    played_at: Option<DateTime<Utc>>,
+    map: &'a str,
+    match_type: &'a str,
+    duration: &'a str,
+    player: &'a str,
+    team_score: &'a str,
+    stats: BTreeMap<&'a str, &'a str>,
+    weapons: &'a [Weapon],
+}
+
+/// Turns a player name into something safe to use as a file name.
+fn history_file_name(name: &str) -> String {
+    let cleaned: String = normalize_name(name)
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if cleaned.is_empty() {
+        "unnamed".to_string()
+    } else {
+        cleaned
+    }
+}
+
+fn append_player_history(dir: &Path, m: &Match) -> Result<(), BotError> {
+    std::fs::create_dir_all(dir)?;
+    for team in &m.teams {
+        for player in &team.players {
+            let entry = HistoryEntry {
+                played_at: m.played_at(),
+                map: &m.map,
+                match_type: &m.match_type,
+                duration: &m.duration,
+                player: &player.name,
+                team_score: &team.score,
+                stats: player
+                    .stats
+                    .iter()
+                    .map(|(n, v)| (n.as_str(), v.as_str()))
+                    .collect(),
+                weapons: &player.weapons,
+            };
+            let path = dir.join(format!("{}.jsonl", history_file_name(&player.name)));
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// How long a zero-byte file gets to fill up before it is skipped.
+const EMPTY_RETRY_DELAY: Duration = Duration::from_millis(1000);
+
+/// How a new file is watched until it looks fully written.
+#[derive(Debug, Clone, Copy)]
+struct Settle {
+    /// Time between size checks.
+    poll: Duration,
+    /// Give up on a file still growing after this long.
+    max_wait: Duration,
+}
+
+/// Reads a match file, giving a zero-byte file (created but not yet written)
+/// one more `retry_delay` to fill up. `None` means it stayed empty.
+async fn read_match_file(fpath: &Path, retry_delay: Duration) -> std::io::Result<Option<String>> {
+    let data = read_file_text(fpath).await?;
+    if !data.is_empty() {
+        return Ok(Some(data));
+    }
+
+    log::debug!("{:?} is still empty, retrying in {:?}", fpath, retry_delay);
+    tokio::time::sleep(retry_delay).await;
+    let data = read_file_text(fpath).await?;
+    if data.is_empty() {
+        log::debug!("{:?} stayed empty, skipping", fpath);
+        return Ok(None);
+    }
+    Ok(Some(data))
+}
+
+/// Reads a file as text, decompressing it first if it ends in `.gz`. A `.gz`
+/// that isn't valid gzip is a read error, so the file is logged and skipped.
+async fn read_file_text(fpath: &Path) -> std::io::Result<String> {
+    let bytes = tokio::fs::read(fpath).await?;
+    if bytes.is_empty() {
+        return Ok(String::new());
+    }
+    if fpath.extension().is_some_and(|ext| ext == "gz") {
+        let mut data = String::new();
+        flate2::read::GzDecoder::new(bytes.as_slice()).read_to_string(&mut data)?;
+        return Ok(data);
+    }
+    String::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Waits for a new file to be fully written: its size has to stay the same
+/// over two polls in a row. Returns `false` if it vanished in the meantime,
+/// which is how temp-then-rename writers look from the outside, or if it
+/// was still changing when `max_wait` ran out.
+async fn settle_file(fpath: &Path, settle: Settle) -> bool {
+    let started = tokio::time::Instant::now();
+    let mut last_size = None;
+    let mut stable_polls = 0;
+    loop {
+        tokio::time::sleep(settle.poll).await;
+        let size = match tokio::fs::metadata(fpath).await {
+            Ok(meta) => meta.len(),
+            Err(_) => {
+                log::debug!("{:?} disappeared before it settled, skipping", fpath);
+                return false;
+            }
+        };
+        if last_size == Some(size) {
+            stable_polls += 1;
+            if stable_polls >= 2 {
+                return true;
+            }
+        } else {
+            stable_polls = 0;
+            last_size = Some(size);
+        }
+        if started.elapsed() >= settle.max_wait {
+            log::warn!(
+                "{:?} was still being written after {:?}, skipping",
+                fpath,
+                settle.max_wait
+            );
+            return false;
+        }
+    }
+}
+
+/// Collapses repeated reports of the same match identity within a window.
+#[derive(Debug)]
+struct MatchThrottle {
+    window: Duration,
+    last_sent: HashMap<String, Instant>,
+}
+
+impl MatchThrottle {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Records the report and returns `true` unless the same identity was
+    /// already reported within the window.
+    fn allow(&mut self, key: &str, now: Instant) -> bool {
+        self.last_sent
+            .retain(|_, sent| now.duration_since(*sent) < self.window);
+        if self.last_sent.contains_key(key) {
+            return false;
+        }
+        self.last_sent.insert(key.to_string(), now);
+        true
+    }
+}
+
+/// Drops watcher events for a path seen within the last `window`, so a write
+/// that fires several events is only picked up once.
+#[derive(Debug)]
+struct EventDebounce {
+    window: Duration,
+    seen: HashMap<PathBuf, Instant>,
+}
+
+impl EventDebounce {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Records the event and returns `true` unless the same path had one
+    /// within the window.
+    fn allow(&mut self, path: &Path, now: Instant) -> bool {
+        self.seen
+            .retain(|_, seen| now.duration_since(*seen) < self.window);
+        if self.seen.contains_key(path) {
+            return false;
+        }
+        self.seen.insert(path.to_path_buf(), now);
+        true
+    }
+}
+
+/// Remembers the last reported match for `--only-on-change`.
+#[derive(Debug, Default)]
+struct LastSent {
+    key: Option<String>,
+}
+
+impl LastSent {
+    /// Records the match and returns `false` if it is the same as the last one.
+    fn changed(&mut self, m: &Match) -> bool {
+        let key = m.scores_key();
+        if self.key.as_ref() == Some(&key) {
+            return false;
+        }
+        self.key = Some(key);
+        true
+    }
+}
+
+/// Spots a map restart: the same map and players as the match before,
+/// played within `window` of it.
+#[derive(Debug)]
+struct RematchDetector {
+    window: chrono::Duration,
+    last: Option<(String, DateTime<Utc>)>,
+}
+
+impl RematchDetector {
+    fn new(window: chrono::Duration) -> Self {
+        Self { window, last: None }
+    }
+
+    /// Records the match and returns whether it repeats the previous one.
+    fn is_rematch(&mut self, m: &Match) -> bool {
+        let key = m.identity_key();
+        let Some(played_at) = m.played_at() else {
+            self.last = None;
+            return false;
+        };
+        let rematch = self.last.as_ref().is_some_and(|(last_key, last_at)| {
+            *last_key == key && (played_at - *last_at).abs() <= self.window
+        });
+        self.last = Some((key, played_at));
+        rematch
+    }
+}
+
+/// Collects items arriving less than `gap` apart into one group.
+#[derive(Debug)]
+struct SessionGroups<T> {
+    gap: Duration,
+    pending: Vec<T>,
+    last: Option<Instant>,
+}
+
+impl<T> SessionGroups<T> {
+    fn new(gap: Duration) -> Self {
+        Self {
+            gap,
+            pending: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn gap_passed(&self, now: Instant) -> bool {
+        self.last
+            .is_some_and(|last| now.duration_since(last) > self.gap)
+    }
+
+    /// Adds `item`, returning the previous group if it arrived after the gap.
+    fn push(&mut self, item: T, now: Instant) -> Option<Vec<T>> {
+        let done = self.flush_due(now);
+        self.pending.push(item);
+        self.last = Some(now);
+        done
+    }
+
+    /// The group so far, once nothing has arrived for longer than the gap.
+    fn flush_due(&mut self, now: Instant) -> Option<Vec<T>> {
+        if !self.gap_passed(now) || self.pending.is_empty() {
+            return None;
+        }
+        self.last = None;
+        Some(std::mem::take(&mut self.pending))
+    }
+
+    /// The group so far, gap or not.
+    fn flush_all(&mut self) -> Vec<T> {
+        self.last = None;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Withholds items until a deadline, then releases them all at once.
+#[derive(Debug)]
+struct StartupGrace<T> {
+    until: Instant,
+    queued: Vec<T>,
+}
+
+impl<T> StartupGrace<T> {
+    fn new(until: Instant) -> Self {
+        Self {
+            until,
+            queued: Vec::new(),
+        }
+    }
+
+    /// Time left in the window, `None` once it has closed.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.until
+            .checked_duration_since(now)
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Queues `item` while the window is open, otherwise hands it back.
+    fn hold(&mut self, item: T, now: Instant) -> Option<T> {
+        if self.remaining(now).is_none() {
+            return Some(item);
+        }
+        self.queued.push(item);
+        None
+    }
+
+    /// Everything queued, once the window has closed.
+    fn release(&mut self, now: Instant) -> Vec<T> {
+        if self.remaining(now).is_some() {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.queued)
+    }
+}
+
+/// What became of a match file, which decides whether it counts as processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileOutcome {
+    /// Nothing usable yet: it never settled, stayed empty, or couldn't be
+    /// read or parsed. A later write may fix that.
+    Unread,
+    /// Reports are waiting for a session or the startup grace to end.
+    Held,
+    /// Every match in it was sent or skipped for good.
+    Done,
+}
+
+/// Everything needed to turn a match file into a sent report.
+struct Reporter {
+    sink: Sink,
+    chat_ids: Vec<ChatId>,
+    parse_opts: ParseOptions,
+    report_opts: Arc<Mutex<ReportOptions>>,
+    filter: MatchFilter,
+    history_dir: Option<PathBuf>,
+    db: Option<MatchDb>,
+    status_file: Option<PathBuf>,
+    folder_tags: bool,
+    store: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    throttle: Option<MatchThrottle>,
+    last_sent: Option<LastSent>,
+    rematches: Option<RematchDetector>,
+    sessions: Option<SessionGroups<Outgoing>>,
+    thread_id: Option<ThreadId>,
+    grace: Option<StartupGrace<(ChatId, Outgoing)>>,
+    settle: Settle,
+    /// Files we already logged a permission error for.
+    unreadable: HashSet<PathBuf>,
+}
+
+impl Reporter {
+    /// The `--chat-id` targets plus every chat that has /subscribe'd. Each
+    /// gets its own sends, so one failing chat doesn't hold up the others.
+    fn recipients(&self) -> Vec<ChatId> {
+        self.store.read(|s| s.recipients(&self.chat_ids))
+    }
+
+    async fn process_file(&mut self, fpath: &Path) -> FileOutcome {
+        if !settle_file(fpath, self.settle).await {
+            return FileOutcome::Unread;
+        }
+        self.process_settled(fpath).await
+    }
+
+    /// `process_file` for a file `settle_file` has already waited for.
+    async fn process_settled(&mut self, fpath: &Path) -> FileOutcome {
+        self.metrics.file_processed();
+        let data = match read_match_file(fpath, EMPTY_RETRY_DELAY).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return FileOutcome::Unread,
+            Err(e) if is_permission_denied(&e) => {
+                if self.unreadable.insert(fpath.to_path_buf()) {
+                    log::error!(
+                        "Permission denied reading {:?}, check which user writes it \
+                         (further errors for this file are not logged)",
+                        fpath
+                    );
+                }
+                return FileOutcome::Unread;
+            }
+            Err(e) => {
+                log::error!("Unable to read file {:?}: {}", fpath, e);
+                return FileOutcome::Unread;
+            }
+        };
+        match parse_all_matches(data, &self.parse_opts) {
+            Ok(matches) => {
+                let file_time = tokio::fs::metadata(fpath)
+                    .await
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .map(DateTime::<Utc>::from);
+                if matches.len() > 1 {
+                    log::info!("{:?} holds {} matches", fpath, matches.len());
+                }
+                let mut outcome = FileOutcome::Done;
+                for mut match_data in matches {
+                    match_data.file_time = file_time;
+                    // stored whether or not the report gets sent
+                    let mut new_record = false;
+                    if let Some(db) = self.db.as_mut() {
+                        match db.insert_match(&match_data) {
+                            Ok(stored) => {
+                                log::debug!("Stored {:?} in the database as #{}", fpath, stored.id);
+                                new_record = stored.new_record;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to store {:?} in the database: {}", fpath, e)
+                            }
+                        }
+                    }
+                    if self.report_match(fpath, match_data, new_record).await {
+                        outcome = FileOutcome::Held;
+                    }
+                }
+                outcome
+            }
+            Err(e) => {
+                self.metrics.parse_failed();
+                log::error!("Error parsing content: {}", e);
+                FileOutcome::Unread
+            }
+        }
+    }
+
+    /// `new_record` adds a banner for a map high score set in this match.
+    /// Returns whether the report is held back rather than sent.
+    async fn report_match(&mut self, fpath: &Path, match_data: Match, new_record: bool) -> bool {
+        log::info!(
+            "Parsed {} match on {} lasting {}",
+            match_data.match_type,
+            match_data.map,
+            humanize_duration(&match_data.duration)
+        );
+        if let Some(dir) = &self.history_dir {
+            if let Err(e) = append_player_history(dir, &match_data) {
+                log::error!("Failed to write player history to {:?}: {}", dir, e);
+            }
+        }
+        if let Some(reason) = self.filter.skip_reason(&match_data) {
+            log::info!("Skipping {:?}: {}", fpath, reason);
+            return false;
+        }
+        if let Some(throttle) = self.throttle.as_mut() {
+            if !throttle.allow(&match_data.identity_key(), Instant::now()) {
+                log::info!("Skipping {:?}: same match reported recently", fpath);
+                return false;
+            }
+        }
+        if let Some(last_sent) = self.last_sent.as_mut() {
+            if !last_sent.changed(&match_data) {
+                log::info!(
+                    "Skipping {:?}: nothing changed since the last report",
+                    fpath
+                );
+                return false;
+            }
+        }
+
+        let opts = self.report_opts.lock().unwrap().clone();
+        let rematch = self
+            .rematches
+            .as_mut()
+            .is_some_and(|r| r.is_rematch(&match_data));
+        let mut msg = if rematch && !opts.kv {
+            log::info!("{:?} looks like a rematch, sending a summary", fpath);
+            Outgoing::new(format!(
+                "\\(rematch\\) {}",
+                format_match_summary(&match_data, &opts)
+            ))
+        } else {
+            render_outgoing(&match_data, &opts)
+        };
+        // plain output is built as MarkdownV2 like the rest, up to the
+        // report number, then stripped in one go
+        let markdown = opts.parse_mode().is_some();
+        let markup = !opts.kv;
+        if new_record {
+            prepend_record_banner(&mut msg.text, &match_data.map, markup);
+        }
+        if let Some(status) = self.status_file.as_deref() {
+            if let Some(status) = read_status(status).await {
+                prepend_status(&mut msg.text, &status, markup);
+            }
+        }
+        if let Some(tag) = folder_tag(fpath).filter(|_| self.folder_tags) {
+            append_folder_tag(msg.last_mut(), &tag, markup);
+        }
+        let archived = self.store.update(|s| {
+            let id = s.next_report_id();
+            append_report_number(msg.last_mut(), id, markup);
+            if markup && !markdown {
+                for part in msg.parts_mut() {
+                    *part = markdown_to_plain(part);
+                }
+            }
+            s.archive_report(
+                id,
+                StoredReport {
+                    title: format!("{} on {}", match_data.match_type, match_data.map),
+                    text: msg.parts().map(String::as_str).collect(),
+                    markdown,
+                },
+            );
+            id
+        });
+        match archived {
+            Ok(id) => log::info!("Stored report as match #{}", id),
+            Err(e) => log::error!("Failed to store report: {}", e),
+        }
+
+        let Some(sessions) = self.sessions.as_mut() else {
+            return self.dispatch(msg).await;
+        };
+        match sessions.push(msg, Instant::now()) {
+            Some(done) => {
+                // this report starts the next session
+                log::info!("Session ended after {} match(es)", done.len());
+                let divider = opts.match_divider.clone();
+                self.dispatch(combine_session(done, markdown, &divider))
+                    .await;
+            }
+            None => log::info!("Holding report until the session ends"),
+        }
+        true
+    }
+
+    /// Sends to every recipient, or queues while the startup grace runs.
+    /// Returns whether anything was queued.
+    async fn dispatch(&mut self, msg: Outgoing) -> bool {
+        let mut queued = false;
+        for chat_id in self.recipients() {
+            let item = (chat_id, msg.clone());
+            let item = match self.grace.as_mut() {
+                Some(grace) => grace.hold(item, Instant::now()),
+                None => Some(item),
+            };
+            match item {
+                Some((chat_id, msg)) => self.deliver(chat_id, msg).await,
+                None => {
+                    log::info!("Queued report for {} until the startup grace ends", chat_id);
+                    queued = true;
+                }
+            }
+        }
+        queued
+    }
+
+    fn parse_mode(&self) -> Option<ParseMode> {
+        self.report_opts.lock().unwrap().parse_mode()
+    }
+
+    /// Sends one message, waiting out a flood limit or following a group
+    /// upgrade once before giving up.
+    async fn send_chunk(
+        &self,
+        chat_id: &mut ChatId,
+        chunk: String,
+        parse_mode: Option<ParseMode>,
+    ) -> Result<MessageId, RequestError> {
+        let send = |chat_id| {
+            self.sink.send_message(
+                chat_id,
+                chunk.clone(),
+                topic_for(chat_id, self.thread_id),
+                parse_mode,
+            )
+        };
+        let mut result = send(*chat_id).await;
+        if let Err(RequestError::RetryAfter(wait)) = &result {
+            log::warn!(
+                "Rate limited in {}, retrying in {}s",
+                chat_id,
+                wait.seconds()
+            );
+            tokio::time::sleep(wait.duration()).await;
+            result = send(*chat_id).await;
+        }
+        if let Some(new_id) = result
+            .as_ref()
+            .err()
+            .and_then(|e| migrate_chat(&self.store, *chat_id, e))
+        {
+            *chat_id = new_id;
+            result = send(new_id).await;
+        }
+        result
+    }
+
+    async fn deliver(&self, mut chat_id: ChatId, msg: Outgoing) {
+        let parse_mode = self.parse_mode();
+        let mut first_sent = None;
+        // awaited one by one, so the parts arrive in order
+        let chunks = msg
+            .parts()
+            .flat_map(|part| split_message(part, MESSAGE_LIMIT))
+            .collect::<Vec<_>>();
+        for chunk in chunks {
+            let result = self.send_chunk(&mut chat_id, chunk, parse_mode).await;
+            match result {
+                Ok(sent) => {
+                    self.metrics.sent();
+                    first_sent.get_or_insert(sent);
+                }
+                Err(err) => {
+                    // the rest wouldn't make sense without this part
+                    self.metrics.send_failed();
+                    log::error!("Failed to send message to {}: {}", chat_id, err);
+                    return;
+                }
+            }
+        }
+
+        let (Some(doc), Some(sent)) = (msg.document, first_sent) else {
+            return;
+        };
+        if let Err(err) = self
+            .sink
+            .send_document(chat_id, doc, sent, topic_for(chat_id, self.thread_id))
+            .await
+        {
+            self.metrics.send_failed();
+            log::error!("Failed to attach the full report in {}: {}", chat_id, err);
+        }
+    }
+
+    fn has_timers(&self) -> bool {
+        self.grace.is_some() || self.sessions.is_some()
+    }
+
+    /// Whether a session or the startup grace is holding back reports.
+    fn holds_reports(&self) -> bool {
+        self.sessions
+            .as_ref()
+            .is_some_and(|s| !s.pending.is_empty())
+            || self.grace.as_ref().is_some_and(|g| !g.queued.is_empty())
+    }
+
+    async fn tick(&mut self) {
+        self.grace_tick().await;
+        self.session_tick().await;
+    }
+
+    /// Sends the current session once the gap has passed without a new match.
+    async fn session_tick(&mut self) {
+        let Some(done) = self
+            .sessions
+            .as_mut()
+            .and_then(|s| s.flush_due(Instant::now()))
+        else {
+            return;
+        };
+        log::info!("Session ended after {} match(es)", done.len());
+        let markdown = self.parse_mode().is_some();
+        let divider = self.report_opts.lock().unwrap().match_divider.clone();
+        self.dispatch(combine_session(done, markdown, &divider))
+            .await;
+    }
+
+    /// Sends everything a session or the startup grace still holds, without
+    /// waiting for them to end, so nothing is lost when the bot stops.
+    async fn flush(&mut self) {
+        if let Some(grace) = self.grace.take() {
+            if !grace.queued.is_empty() {
+                log::info!(
+                    "Cutting the startup grace short, sending {} queued report(s)",
+                    grace.queued.len()
+                );
+            }
+            for (chat_id, msg) in grace.queued {
+                self.deliver(chat_id, msg).await;
+            }
+        }
+        let Some(done) = self.sessions.as_mut().map(SessionGroups::flush_all) else {
+            return;
+        };
+        if done.is_empty() {
+            return;
+        }
+        log::info!("Sending the unfinished session of {} match(es)", done.len());
+        let markdown = self.parse_mode().is_some();
+        let divider = self.report_opts.lock().unwrap().match_divider.clone();
+        self.dispatch(combine_session(done, markdown, &divider))
+            .await;
+    }
+
+    /// Logs the countdown while the startup grace is running and sends
+    /// everything it held back once it ends.
+    async fn grace_tick(&mut self) {
+        let Some(grace) = self.grace.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(left) = grace.remaining(now) {
+            let secs = left.as_secs_f64().ceil() as u64;
+            if secs.is_multiple_of(10) || secs <= 3 {
+                log::info!(
+                    "Startup grace: {}s left, {} report(s) queued",
+                    secs,
+                    grace.queued.len()
+                );
+            }
+            return;
+        }
+        let queued = grace.release(now);
+        self.grace = None;
+        log::info!(
+            "Startup grace over, sending {} queued report(s)",
+            queued.len()
+        );
+        for (chat_id, msg) in queued {
+            self.deliver(chat_id, msg).await;
+        }
+    }
+}
+
+/// Follows a group's upgrade to a supergroup: when `err` says `chat_id` has
+/// moved, the new id is saved to the state and returned so the send can be
+/// retried there.
+fn migrate_chat(store: &StateStore, chat_id: ChatId, err: &RequestError) -> Option<ChatId> {
+    let RequestError::MigrateToChatId(new_id) = err else {
+        return None;
+    };
+    log::warn!(
+        "Chat {} was upgraded to supergroup {}, following it",
+        chat_id,
+        new_id
+    );
+    if let Err(e) = store.update(|s| s.migrate_chat(chat_id, *new_id)) {
+        log::error!("Unable to save the new chat id: {}", e);
+    }
+    Some(*new_id)
+}
+
+/// Telegram's cap on the length of a single message, in characters.
+const MESSAGE_LIMIT: usize = 4096;
+
+/// Splits a report into messages of at most `limit` characters, breaking
+/// between lines where possible. A code block cut in two is closed at the end
+/// of one message and reopened at the start of the next.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    const FENCE: &str = "```\n";
+    // a line longer than this can't share a message with a reopened fence
+    let max_piece = limit.saturating_sub(2 * FENCE.len()).max(1);
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_len = 0;
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        for piece in split_long_line(line, max_piece) {
+            let len = piece.chars().count();
+            let closing = if in_code { FENCE.len() } else { 0 };
+            if chunk_len > 0 && chunk_len + len + closing > limit {
+                if in_code {
+                    chunk.push_str(FENCE);
+                }
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_len = 0;
+                if in_code {
+                    chunk.push_str(FENCE);
+                    chunk_len = FENCE.len();
+                }
+            }
+            chunk.push_str(piece);
+            chunk_len += len;
+        }
+        if line.starts_with("```") {
+            in_code = !in_code;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Cuts a line into pieces of at most `max` characters, never right after a
+/// backslash so a MarkdownV2 escape stays with the character it escapes.
+fn split_long_line(line: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while rest.chars().count() > max {
+        let mut cut = rest.char_indices().nth(max).map_or(rest.len(), |(i, _)| i);
+        let backslashes = rest[..cut].chars().rev().take_while(|&c| c == '\\').count();
+        if backslashes % 2 == 1 && cut > 1 {
+            cut -= 1;
+        }
+        let (piece, tail) = rest.split_at(cut);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces.push(rest);
+    pieces
+}
+
+/// Where reports go. Tests record the sends instead of talking to Telegram.
+#[derive(Debug, Clone)]
+enum Sink {
+    Telegram(Bot),
+    /// `--dry-run`: every message is printed, exactly as it would be sent.
+    Stdout,
+    #[cfg(test)]
+    Recording(SentLog),
+}
+
+/// Every message a recording sink was asked to send, in order.
+#[cfg(test)]
+type SentLog = Arc<Mutex<Vec<(ChatId, String)>>>;
+
+impl Sink {
+    /// Sends `text` in as many messages as `MESSAGE_LIMIT` needs, each with
+    /// the plain-text fallback, stopping at the first that fails.
+    async fn send_split(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        thread_id: Option<ThreadId>,
+        parse_mode: Option<ParseMode>,
+    ) -> Result<(), RequestError> {
+        for chunk in split_message(text, MESSAGE_LIMIT) {
+            self.send_message(chat_id, chunk, thread_id, parse_mode)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        thread_id: Option<ThreadId>,
+        parse_mode: Option<ParseMode>,
+    ) -> Result<MessageId, RequestError> {
+        match self {
+            Sink::Telegram(bot) => send_report(bot, chat_id, text, thread_id, parse_mode)
+                .await
+                .map(|sent| sent.id),
+            Sink::Stdout => {
+                println!("--- message to {} ---\n{}", chat_id, text);
+                Ok(MessageId(0))
+            }
+            #[cfg(test)]
+            Sink::Recording(sent) => {
+                let mut sent = sent.lock().unwrap();
+                sent.push((chat_id, text));
+                Ok(MessageId(sent.len() as i32))
+            }
+        }
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        doc: Attachment,
+        reply_to: MessageId,
+        thread_id: Option<ThreadId>,
+    ) -> Result<(), RequestError> {
+        match self {
+            Sink::Telegram(bot) => {
+                let mut request = bot
+                    .send_document(
+                        chat_id,
+                        InputFile::memory(doc.contents).file_name(doc.file_name),
+                    )
+                    .reply_parameters(ReplyParameters::new(reply_to));
+                if let Some(thread_id) = thread_id {
+                    request = request.message_thread_id(thread_id);
+                }
+                request.await.map(drop)
+            }
+            Sink::Stdout => {
+                println!(
+                    "--- {} attached for {} ---\n{}",
+                    doc.file_name, chat_id, doc.contents
+                );
+                Ok(())
+            }
+            #[cfg(test)]
+            Sink::Recording(sent) => {
+                sent.lock().unwrap().push((chat_id, doc.contents));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Telegram rejects MarkdownV2 messages with unbalanced entities that slip
+/// past `escape_markdown`; those are still worth delivering as plain text.
+fn should_fallback_to_plain(err: &RequestError) -> bool {
+    matches!(err, RequestError::Api(ApiError::CantParseEntities(_)))
+}
+
+/// The forum topic to post into in `chat_id`. Only groups have topics, so a
+/// private chat (positive id) always gets its main thread.
+fn topic_for(chat_id: ChatId, thread_id: Option<ThreadId>) -> Option<ThreadId> {
+    if chat_id.is_user() && thread_id.is_some() {
+        log::debug!("Not using --message-thread-id in private chat {}", chat_id);
+        return None;
+    }
+    thread_id
+}
+
+fn build_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: String,
+    thread_id: Option<ThreadId>,
+) -> <Bot as Requester>::SendMessage {
+    let request = bot.send_message(chat_id, text);
+    match thread_id {
+        Some(thread_id) => request.message_thread_id(thread_id),
+        None => request,
+    }
+}
+
+async fn send_report(
+    bot: &Bot,
+    chat_id: ChatId,
+    msg: String,
+    thread_id: Option<ThreadId>,
+    parse_mode: Option<ParseMode>,
+) -> Result<Message, RequestError> {
+    let Some(parse_mode) = parse_mode else {
+        return build_message(bot, chat_id, msg, thread_id).await;
+    };
+    match build_message(bot, chat_id, msg.clone(), thread_id)
+        .parse_mode(parse_mode)
+        .await
+    {
+        Ok(sent) => Ok(sent),
+        Err(err) if should_fallback_to_plain(&err) => {
+            log::warn!("MarkdownV2 rejected ({}), resending as plain text", err);
+            build_message(bot, chat_id, msg, thread_id).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Knobs that let `parse_content` cope with mod-specific XML.
+#[derive(Debug, Clone)]
+struct ParseOptions {
+    /// Attribute names tried in order for a stat's value before falling back
+    /// to the element text.
+    stat_value_attrs: Vec<String>,
+    /// Stats tried in order for a teamless player's place when it has no
+    /// score.
+    placement_stats: Vec<String>,
+    /// Element names read as `<stat>` and `<weapon>`.
+    stat_elements: Vec<String>,
+    weapon_elements: Vec<String>,
+    /// Stats dropped while parsing.
+    banned_stats: HashSet<String>,
+    duplicate_stat_policy: DuplicateStatPolicy,
+    /// Canonical player names by normalized alias.
+    aliases: HashMap<String, String>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            stat_value_attrs: vec!["value".to_string(), "val".to_string(), "amount".to_string()],
+            placement_stats: vec!["Rank".to_string(), "Place".to_string()],
+            stat_elements: vec!["stat".to_string()],
+            weapon_elements: vec!["weapon".to_string()],
+            banned_stats: BANNED_STATS.iter().map(|s| s.to_string()).collect(),
+            duplicate_stat_policy: DuplicateStatPolicy::default(),
+            aliases: HashMap::new(),
+        }
+    }
+}
+
+impl ParseOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            stat_value_attrs: args.stat_value_attrs.clone(),
+            placement_stats: args.placement_stats.clone(),
+            stat_elements: args.stat_elements.clone(),
+            weapon_elements: args.weapon_elements.clone(),
+            banned_stats: {
+                let mut banned: HashSet<String> = match &args.hide_stats {
+                    Some(stats) => stats.iter().cloned().collect(),
+                    None => BANNED_STATS.iter().map(|s| s.to_string()).collect(),
+                };
+                for stat in &args.show_stats {
+                    banned.remove(stat);
+                }
+                banned
+            },
+            duplicate_stat_policy: args.duplicate_stat_policy,
+            aliases: args
+                .aliases
+                .iter()
+                .map(|(alias, name)| (normalize_name(alias), name.clone()))
+                .collect(),
+        }
+    }
+
+    fn is_stat_element(&self, name: &[u8]) -> bool {
+        self.stat_elements.iter().any(|n| n.as_bytes() == name)
+    }
+
+    fn is_weapon_element(&self, name: &[u8]) -> bool {
+        self.weapon_elements.iter().any(|n| n.as_bytes() == name)
+    }
+
+    fn stat_value(&self, attr_map: &HashMap<Vec<u8>, Vec<u8>>) -> Option<String> {
+        self.stat_value_attrs
+            .iter()
+            .find_map(|name| attr_map.get(name.as_bytes()))
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+}
+
+/// The attributes of `e`, also taking unquoted values (`map=q3dm6`) from
+/// hand-edited files. Such a value ends at the first space, so it can't hold
+/// one; an attribute that can't be read at all is skipped.
+fn attributes<'a>(e: &'a BytesStart) -> impl Iterator<Item = Attribute<'a>> {
+    e.html_attributes().flatten()
+}
+
+fn attr_map(e: &BytesStart) -> HashMap<Vec<u8>, Vec<u8>> {
+    attributes(e)
+        .map(|attr| (attr.key.into_inner().to_vec(), attr.value.to_vec()))
+        .collect()
+}
+
+fn push_stat(player: Option<&mut Player>, name: String, val: String, opts: &ParseOptions) {
+    if opts.banned_stats.contains(&name) {
+        return;
+    }
+    if let Some(player) = player {
+        player.stats.push((name, val));
+    }
+}
+
+/// Adds the weapon described by a `<weapon>` element's attributes.
+fn push_weapon(player: Option<&mut Player>, attrs: &HashMap<Vec<u8>, Vec<u8>>) {
+    let (Some(player), Some(name_bytes)) = (player, attrs.get(b"name".as_ref())) else {
+        return;
+    };
+    let count = |key: &[u8]| {
+        attrs
+            .get(key)
+            .map(|b| parse_count(&String::from_utf8_lossy(b)))
+            .unwrap_or(0)
+    };
+    let weapon = Weapon {
+        name: String::from_utf8_lossy(name_bytes).into_owned(),
+        hits: count(b"hits"),
+        shots: count(b"shots"),
+        kills: count(b"kills"),
+    };
+    if weapon.hits > 0 && weapon.shots == 0 {
+        log::warn!(
+            "{} has {} hits but no shots with {}, showing its accuracy as N/A",
+            player.name,
+            weapon.hits,
+            weapon.name
+        );
+    }
+    player.weapons.push(weapon);
+}
+
+/// A server limit attribute; `0` means the limit is off.
+fn parse_limit(value: &[u8]) -> Option<u32> {
+    Some(parse_count(&String::from_utf8_lossy(value))).filter(|&limit| limit > 0)
+}
+
+/// The first match in `data`; see `parse_all_matches` for files that batch
+/// several.
+fn parse_content(data: String, opts: &ParseOptions) -> Result<Match, BotError> {
+    parse_all_matches(data, opts).map(|mut matches| matches.swap_remove(0))
+}
+
+/// Every match in `data`, one per `<match>` element, in file order. Errors
+/// if none of them has any content.
+fn parse_all_matches(data: String, opts: &ParseOptions) -> Result<Vec<Match>, BotError> {
+    let mut reader = Reader::from_str(&data);
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut matches = Vec::new();
+    let mut game_match = Match::default();
+    let mut seen_match = false;
+
+    let mut current_team: Option<Team> = None;
+    let mut current_player: Option<Player> = None;
+    // `<stat name="..">value</stat>` waiting for its text
+    let mut pending_stat: Option<String> = None;
+    // element depth, so a `</team>` or `</players>` only closes the element
+    // that opened it
+    let mut depth = 0usize;
+    let mut team_depth: Option<usize> = None;
+    // `<teams score="..">` fills in teams that don't carry their own score
+    let mut default_team_score: Option<String> = None;
+    // `<players team="..">` outside a `<team>` assigns its players by name
+    let mut players_team: Option<(usize, String)> = None;
+
+    loop {
+        let event = reader.read_event_into(&mut buf);
+        let closes = matches!(event, Ok(Event::End(_)));
+        if let Ok(Event::Start(_)) = event {
+            depth += 1;
+        }
+        match event {
+            Err(e) => {
+                return Err(BotError::Parse(format!(
+                    "error at position {}: {:?}",
+                    reader.error_position(),
+                    e
+                )))
+            }
+            Ok(Event::Eof) => break,
+
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"match" => {
+                    // a new match starts from scratch
+                    if seen_match {
+                        matches.push(std::mem::take(&mut game_match));
+                        current_team = None;
+                        current_player = None;
+                        pending_stat = None;
+                        team_depth = None;
+                        default_team_score = None;
+                        players_team = None;
+                    }
+                    seen_match = true;
+                    for attr in attributes(&e) {
+                        match attr.key.into_inner() {
+                            b"map" => {
+                                game_match.map =
+                                    strip_color_codes(&String::from_utf8_lossy(&attr.value))
+                            }
+                            b"server" | b"hostname" => {
+                                game_match.server =
+                                    strip_color_codes(&String::from_utf8_lossy(&attr.value))
+                            }
+                            b"type" => {
+                                game_match.match_type =
+                                    String::from_utf8_lossy(&attr.value).into_owned()
+                            }
+                            b"ruleset" | b"gametype" => {
+                                game_match.ruleset =
+                                    Some(String::from_utf8_lossy(&attr.value).into_owned())
+                                        .filter(|r| !r.is_empty())
+                            }
+                            b"duration" => {
+                                game_match.duration =
+                                    String::from_utf8_lossy(&attr.value).into_owned()
+                            }
+                            b"isTeamGame" => {
+                                game_match.is_team_game = String::from_utf8_lossy(&attr.value)
+                                    .parse()
+                                    .unwrap_or(false)
+                            }
+                            b"startTime" => {
+                                game_match.start_time =
+                                    parse_timestamp(&String::from_utf8_lossy(&attr.value))
+                            }
+                            b"endTime" => {
+                                game_match.end_time =
+                                    parse_timestamp(&String::from_utf8_lossy(&attr.value))
+                            }
+                            b"timestamp" => {
+                                game_match.timestamp =
+                                    String::from_utf8_lossy(&attr.value).trim().parse().ok()
+                            }
+                            b"fraglimit" | b"fragLimit" => {
+                                game_match.frag_limit = parse_limit(&attr.value)
+                            }
+                            b"timelimit" | b"timeLimit" => {
+                                game_match.time_limit = parse_limit(&attr.value)
+                            }
+                            b"capturelimit" | b"captureLimit" => {
+                                game_match.capture_limit = parse_limit(&attr.value)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                b"team" => {
+                    let mut team = Team::default();
+                    for attr in attributes(&e) {
+                        match attr.key.into_inner() {
+                            b"score" => {
+                                team.score = String::from_utf8_lossy(&attr.value).into_owned()
+                            }
+                            b"name" => {
+                                team.name = strip_color_codes(&String::from_utf8_lossy(&attr.value))
+                            }
+                            b"color" => {
+                                team.color = String::from_utf8_lossy(&attr.value).into_owned()
+                            }
+                            _ => {}
+                        }
+                    }
+                    current_team = Some(team);
+                    team_depth = Some(depth);
+                }
+                b"teams" => {
+                    let attrs = attr_map(&e);
+                    if let Some(score) = attrs
+                        .get(b"score".as_ref())
+                        .or(attrs.get(b"defaultScore".as_ref()))
+                    {
+                        default_team_score = Some(String::from_utf8_lossy(score).into_owned());
+                    }
+                }
+                b"players" if current_team.is_none() => {
+                    if let Some(name) = attr_map(&e).get(b"team".as_ref()) {
+                        players_team =
+                            Some((depth, strip_color_codes(&String::from_utf8_lossy(name))));
+                    }
+                }
+                b"player" => {
+                    let mut player = Player::default();
+                    for attr in attributes(&e) {
+                        if attr.key.into_inner() == b"name" {
+                            player.name = canonicalize_name(
+                                &strip_color_codes(&String::from_utf8_lossy(&attr.value)),
+                                &opts.aliases,
+                            );
+                        }
+                    }
+                    current_player = Some(player);
+                }
+                name if opts.is_stat_element(name) => {
+                    let attrs = attr_map(&e);
+                    if let Some(name_bytes) = attrs.get(b"name".as_ref()) {
+                        let name = String::from_utf8_lossy(name_bytes).into_owned();
+                        match opts.stat_value(&attrs) {
+                            Some(val) => push_stat(current_player.as_mut(), name, val, opts),
+                            None => pending_stat = Some(name),
+                        }
+                    }
+                }
+                // `<weapon ...></weapon>`, same as the self-closing form
+                name if opts.is_weapon_element(name) => {
+                    push_weapon(current_player.as_mut(), &attr_map(&e))
+                }
+                _ => {}
+            },
+
+            Ok(Event::Text(e)) => {
+                if let Some(name) = pending_stat.take() {
+                    let val = e.unescape()?.into_owned();
+                    push_stat(current_player.as_mut(), name, val, opts);
+                }
+            }
+
+            Ok(Event::End(e)) => match e.name().local_name().as_ref() {
+                name if opts.is_stat_element(name) => pending_stat = None,
+                b"team" if team_depth == Some(depth) => {
+                    team_depth = None;
+                    if let Some(mut team) = current_team.take() {
+                        if team.score.is_empty() {
+                            team.score = default_team_score.clone().unwrap_or_default();
+                        }
+                        game_match.teams.push(team);
+                    }
+                }
+                b"players" if players_team.as_ref().is_some_and(|(d, _)| *d == depth) => {
+                    players_team = None;
+                }
+                b"player" => {
+                    if let Some(mut player) = current_player.take() {
+                        player.merge_duplicate_stats(opts.duplicate_stat_policy);
+                        if let Some(team) = current_team.as_mut() {
+                            team.players.push(player);
+                        } else if let Some((_, name)) = &players_team {
+                            let idx = match game_match.find_team(name) {
+                                Some(idx) => idx,
+                                None => {
+                                    game_match.teams.push(Team {
+                                        name: name.clone(),
+                                        score: default_team_score.clone().unwrap_or_default(),
+                                        ..Default::default()
+                                    });
+                                    game_match.teams.len() - 1
+                                }
+                            };
+                            game_match.teams[idx].players.push(player);
+                        } else {
+                            // player outside of a team (e.g. 1v1 or ffa)
+                            let mut team = Team::default();
+                            if let Some((_, score)) =
+                                player.stats.iter().find(|(n, _)| n == "Score")
+                            {
+                                team.score = score.clone();
+                            } else {
+                                // some exporters write places as floats ("1.0")
+                                team.place = opts.placement_stats.iter().find_map(|name| {
+                                    player
+                                        .stat(name)
+                                        .and_then(parse_number)
+                                        .filter(|&v| v >= 0.0)
+                                        .map(|v| v.round() as u32)
+                                });
+                            }
+                            team.players.push(player);
+                            game_match.teams.push(team);
+                        }
+                    }
+                }
+                _ => {}
+            },
+
+            Ok(Event::Empty(e)) => {
+                let attr_map = attr_map(&e);
+
+                match e.name().as_ref() {
+                    name if opts.is_stat_element(name) => {
+                        if let (Some(name_bytes), Some(val)) =
+                            (attr_map.get(b"name".as_ref()), opts.stat_value(&attr_map))
+                        {
+                            let name = String::from_utf8_lossy(name_bytes).into_owned();
+                            push_stat(current_player.as_mut(), name, val, opts);
+                        }
+                    }
+                    name if opts.is_weapon_element(name) => {
+                        push_weapon(current_player.as_mut(), &attr_map)
+                    }
+                    b"death" => {
+                        if let (Some(weapon_bytes), Some(player)) =
+                            (attr_map.get(b"weapon".as_ref()), current_player.as_mut())
+                        {
+                            let count = attr_map
+                                .get(b"count".as_ref())
+                                .map(|b| parse_count(&String::from_utf8_lossy(b)))
+                                .unwrap_or(1);
+                            player
+                                .deaths_by_weapon
+                                .push((String::from_utf8_lossy(weapon_bytes).into_owned(), count));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => (),
+        }
+        if closes {
+            depth = depth.saturating_sub(1);
+        }
+        buf.clear();
+    }
+
+    matches.push(game_match);
+    matches.retain(|m| !m.map.is_empty() || !m.teams.is_empty());
+    if matches.is_empty() {
+        return Err(BotError::Parse("no output generated from XML".to_string()));
+    }
+
+    Ok(matches)
+}
+
+/// The report as delivery would build it, for `--test-file` and `/preview`,
+/// minus the banners and report number that only a real report gets.
+fn render_report(m: &Match, opts: &ReportOptions) -> Outgoing {
+    let mut msg = render_outgoing(m, opts);
+    if !opts.kv && opts.parse_mode().is_none() {
+        for part in msg.parts_mut() {
+            *part = markdown_to_plain(part);
+        }
+    }
+    msg
+}
+
+/// What gets posted for one match.
+#[derive(Debug, Clone, PartialEq)]
+struct Outgoing {
+    text: String,
+    /// Further messages sent right after `text`; one per team with
+    /// `--message-per-team`.
+    follow_ups: Vec<String>,
+    /// Sent as a reply to `text` with `--summary-with-document`.
+    document: Option<Attachment>,
+}
+
+impl Outgoing {
+    fn new(text: String) -> Self {
+        Self {
+            text,
+            follow_ups: Vec::new(),
+            document: None,
+        }
+    }
+
+    /// Every message, in the order they go out.
+    fn parts(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.text).chain(&self.follow_ups)
+    }
+
+    fn parts_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        std::iter::once(&mut self.text).chain(&mut self.follow_ups)
+    }
+
+    /// The message that closes the report, where the footer goes.
+    fn last_mut(&mut self) -> &mut String {
+        self.follow_ups.last_mut().unwrap_or(&mut self.text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Attachment {
+    file_name: String,
+    contents: String,
+}
+
+fn render_outgoing(m: &Match, opts: &ReportOptions) -> Outgoing {
+    if opts.compact && !opts.summary_with_document && !opts.kv {
+        return Outgoing::new(format_match_summary(m, opts));
+    }
+    if opts.kv {
+        return Outgoing::new(format_match_kv(m));
+    }
+    if !opts.summary_with_document {
+        if opts.message_per_team && m.is_team_game {
+            let mut sections = format_match_sections(m, opts);
+            let text = sections.remove(0);
+            return Outgoing {
+                text,
+                follow_ups: sections,
+                document: None,
+            };
+        }
+        return Outgoing::new(format_match_report(m, opts));
+    }
+    Outgoing {
+        text: format_match_summary(m, opts),
+        follow_ups: Vec::new(),
+        document: Some(Attachment {
+            file_name: format!("{}.txt", history_file_name(&m.map)),
+            contents: markdown_to_plain(&format_match_report(m, opts)),
+        }),
+    }
+}
+
+/// The server status from `--status-file`, folded onto one line. A missing
+/// or empty file just means there's nothing to show.
+async fn read_status(path: &Path) -> Option<String> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::debug!("No server status from {:?}: {}", path, e);
+            return None;
+        }
+    };
+    let lines: Vec<&str> = data
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" · "))
+}
+
+fn prepend_status(text: &mut String, status: &str, markdown: bool) {
+    let line = if markdown {
+        format!("📡 {}\n", escape_markdown(status))
+    } else {
+        format!("status={}\n", kv_value(status))
+    };
+    text.insert_str(0, &line);
+}
+
+fn prepend_record_banner(text: &mut String, map: &str, markdown: bool) {
+    let line = if markdown {
+        format!("🏆 New {} record\\!\n", escape_markdown(map))
+    } else {
+        format!("map_record={}\n", kv_value(map))
+    };
+    text.insert_str(0, &line);
+}
+
+/// Lowercase words joined by `-`: `Duel Server` becomes `duel-server`.
+/// Anything but letters and digits counts as a gap between words.
+fn slugify(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// The `--folder-tags` tag for a match file: its folder's name, slugified.
+fn folder_tag(fpath: &Path) -> Option<String> {
+    let folder = fpath.parent()?.file_name()?.to_string_lossy();
+    Some(slugify(&folder)).filter(|tag| !tag.is_empty())
+}
+
+fn append_folder_tag(text: &mut String, tag: &str, markdown: bool) {
+    if markdown {
+        text.push_str(&format!("{}\n", escape_markdown(&format!("#{}", tag))));
+    } else {
+        text.push_str(&format!("folder={}\n", kv_value(tag)));
+    }
+}
+
+/// Footer numbering the report so it can be referred to (and fetched again
+/// with `/match <id>`).
+fn append_report_number(text: &mut String, id: u64, markdown: bool) {
+    if markdown {
+        text.push_str(&format!("\\#{}\n", id));
+    } else {
+        text.push_str(&format!("report={}\n", id));
+    }
+}
+
+/// One message for a session: a header and each match as a sub-section.
+/// A single-match session is sent as it is.
+fn combine_session(mut reports: Vec<Outgoing>, markdown: bool, divider: &str) -> Outgoing {
+    if reports.len() == 1 {
+        return reports.remove(0);
+    }
+
+    let count = reports.len();
+    let heading = |text: String| {
+        if markdown {
+            format!("*{}*\n", escape_markdown(&text))
+        } else {
+            format!("{}\n", text)
+        }
+    };
+    let mut text = heading(format!("Session: {} matches", count));
+    let mut documents = Vec::new();
+    for (i, report) in reports.into_iter().enumerate() {
+        if i > 0 && !divider.is_empty() {
+            // a report cut off inside a code block would swallow the divider
+            if markdown && text.matches("```").count() % 2 == 1 {
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push_str("```\n");
+            }
+            let divider = if markdown {
+                escape_markdown(divider)
+            } else {
+                divider.to_string()
+            };
+            text.push_str(&format!("\n{}\n", divider));
+        }
+        text.push('\n');
+        text.push_str(&heading(format!("Match {}/{}", i + 1, count)));
+        for part in report.parts() {
+            text.push_str(part);
+        }
+        documents.extend(report.document);
+    }
+    let document = (!documents.is_empty()).then(|| Attachment {
+        file_name: format!("session-{}", documents[0].file_name),
+        contents: documents
+            .iter()
+            .map(|d| d.contents.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    });
+    Outgoing {
+        text,
+        follow_ups: Vec::new(),
+        document,
+    }
+}
+
+/// Drops MarkdownV2 formatting (bold, italics, code fences and escapes) so a
+/// report reads cleanly as plain text. Every plain-text rendering goes
+/// through here, so the report itself is only ever written once.
+fn markdown_to_plain(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        if line.trim_end() == "```" {
+            // a closed block keeps a blank line to set it apart
+            if in_code {
+                plain.push('\n');
+            }
+            in_code = !in_code;
+            continue;
+        }
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => plain.extend(chars.next()),
+                // bold and italics outside code blocks; inside them the
+                // markers are literal
+                '*' | '_' | '`' if !in_code => {}
+                _ => plain.push(c),
+            }
+        }
+    }
+    plain
+}
+
+/// A few scannable lines: map, type, duration and the scores.
+fn format_match_summary(m: &Match, opts: &ReportOptions) -> String {
+    let mut output = format!(
+        "*{} on {}*, {}\n",
+        escape_markdown(&m.match_type),
+        escape_markdown(&m.map),
+        escape_markdown(&format_duration(&m.duration))
+    );
+    let mut teams: Vec<&Team> = m.teams.iter().collect();
+    if m.is_team_game {
+        let scores: Vec<String> = teams
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let emoji = opts
+                    .team_emojis
+                    .get(&t.color.to_lowercase())
+                    .map(|e| format!("{} ", e))
+                    .unwrap_or_default();
+                // the same fallback as the full report's headers
+                let name = if !t.name.is_empty() {
+                    t.name.clone()
+                } else if !t.color.is_empty() {
+                    capitalize(&t.color)
+                } else if i == 0 {
+                    "Team One".to_string()
+                } else {
+                    "Team Two".to_string()
+                };
+                format!(
+                    "{}{}: *{}*",
+                    emoji,
+                    escape_markdown(&name),
+                    escape_markdown(&t.score)
+                )
+            })
+            .collect();
+        output.push_str(&scores.join(r" \| "));
+        if match_outcome(m) == Outcome::Draw {
+            output.push_str(" \\(draw\\)");
+        }
+    } else {
+        teams.sort_by(|a, b| ffa_order(a, b));
+        let top: Vec<String> = teams
+            .iter()
+            .flat_map(|t| t.players.iter().map(move |p| (p, t)))
+            .take(3)
+            .map(|(p, t)| format!("{} {}", opts.display_name(&p.name), t.score))
+            .collect();
+        output.push_str(&escape_markdown(&top.join(", ")));
+    }
+    output.push('\n');
+    output
+}
+
+/// Quotes values that would otherwise break the one-pair-per-line format.
+fn kv_value(value: &str) -> String {
+    let needs_quoting =
+        value.is_empty() || value.contains(['\n', '\r', '"', '\\']) || value.trim() != value;
+    if !needs_quoting {
+        return value.to_string();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+fn kv_key(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Plain `key=value` lines for shell scripts, one block per match.
+fn format_match_kv(m: &Match) -> String {
+    let mut lines = vec![
+        format!("map={}", kv_value(&m.map)),
+        format!("type={}", kv_value(&m.match_type)),
+        format!("duration={}", kv_value(&m.duration)),
+    ];
+    if let Some(ruleset) = &m.ruleset {
+        lines.push(format!("ruleset={}", kv_value(ruleset)));
+    }
+    if let Some(played_at) = m.played_at() {
+        lines.push(format!("played_at={}", played_at.to_rfc3339()));
+    }
+    for (t, team) in m.teams.iter().enumerate() {
+        let team_key = format!("team{}", t + 1);
+        lines.push(format!("{}_score={}", team_key, kv_value(&team.score)));
+        if !team.name.is_empty() {
+            lines.push(format!("{}_name={}", team_key, kv_value(&team.name)));
+        }
+        for (p, player) in team.players.iter().enumerate() {
+            let player_key = format!("{}_player{}", team_key, p + 1);
+            lines.push(format!("{}_name={}", player_key, kv_value(&player.name)));
+            for (name, val) in &player.stats {
+                lines.push(format!("{}_{}={}", player_key, kv_key(name), kv_value(val)));
+            }
+            for w in &player.weapons {
+                let weapon_key = format!("{}_{}", player_key, kv_key(&w.name));
+                lines.push(format!("{}_shots={}", weapon_key, w.shots));
+                lines.push(format!("{}_hits={}", weapon_key, w.hits));
+                lines.push(format!("{}_kills={}", weapon_key, w.kills));
+            }
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Abbreviations seen while rendering a report, in first-use order.
+#[derive(Debug, Default)]
+struct Legend(Vec<(&'static str, &'static str)>);
+
+impl Legend {
+    fn note(&mut self, abbr: &'static str, meaning: &'static str) {
+        if !self.0.iter().any(|(a, _)| *a == abbr) {
+            self.0.push((abbr, meaning));
+        }
+    }
+
+    fn note_weapon(&mut self, name: &str) {
+        if let Some((abbr, full)) = WEAPON_NAMES.iter().find(|(abbr, _)| *abbr == name) {
+            self.note(abbr, full);
+        }
+    }
+
+    fn render(&self) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|(abbr, meaning)| format!("{} = {}", abbr, meaning))
+            .collect();
+        Some(format!(
+            "_Legend:_ {}\n",
+            escape_markdown(&entries.join(", "))
+        ))
+    }
+}
+
+fn is_sharpshooter(w: &Weapon, opts: &ReportOptions) -> bool {
+    let Some(thresholds) = &opts.sharpshooter else {
+        return false;
+    };
+    thresholds
+        .get(&w.name.to_uppercase())
+        .is_some_and(|min| w.shots > 0 && w.accuracy().is_some_and(|acc| acc >= *min))
+}
+
+/// One ranked table for free-for-all matches instead of per-team headers.
+/// Highest score first; teamless players without a score go by their place.
+fn ffa_order(a: &Team, b: &Team) -> Ordering {
+    let score = |t: &Team| parse_number(&t.score).unwrap_or(f64::NEG_INFINITY);
+    let place = |t: &Team| t.place.unwrap_or(u32::MAX);
+    score(b)
+        .total_cmp(&score(a))
+        .then_with(|| place(a).cmp(&place(b)))
+}
+
+/// The winner and runner-up of a sorted FFA scoreboard when the win came by
+/// no more than `margin` points. Ties don't count.
+fn photo_finish<'a>(teams: &[&'a Team], margin: f64) -> Option<(&'a str, &'a str)> {
+    let [first, second, ..] = teams else {
+        return None;
+    };
+    let gap = parse_number(&first.score)? - parse_number(&second.score)?;
+    if gap <= 0.0 || gap > margin {
+        return None;
+    }
+    Some((&first.players.first()?.name, &second.players.first()?.name))
+}
+
+fn format_ffa_scoreboard(teams: &[&Team], opts: &ReportOptions) -> String {
+    let players: Vec<(String, &str)> = teams
+        .iter()
+        .flat_map(|t| {
+            t.players
+                .iter()
+                .map(|p| (opts.display_name(&p.name), t.score.as_str()))
+        })
+        .collect();
+    let width = players
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut output = String::from("*Scoreboard*\n```\n");
+    for (rank, (name, score)) in players.iter().enumerate() {
+        output.push_str(&format!(
+            "{:>2}. {:<width$}  {}\n",
+            rank + 1,
+            name,
+            escape_markdown(score)
+        ));
+    }
+    output.push_str("```\n");
+    output
+}
+
+/// Sorts weapons by their position in `order`, with weapons not listed there
+/// after them in alphabetical order.
+fn sort_weapons(weapons: &mut [Weapon], order: &[&str]) {
+    weapons.sort_by_cached_key(|w| {
+        let rank = order
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(&w.name))
+            .unwrap_or(order.len());
+        (rank, w.name.to_uppercase())
+    });
+}
+
+/// Rounded percentage of `total` kills, `None` when nobody scored.
+fn frag_share(kills: u32, total: u32) -> Option<u32> {
+    (total > 0).then(|| (kills as f64 * 100.0 / total as f64).round() as u32)
+}
+
+/// Every player's weapons added up per weapon, most kills first.
+fn weapon_meta(m: &Match, opts: &ReportOptions) -> Vec<Weapon> {
+    let mut totals: BTreeMap<String, Weapon> = BTreeMap::new();
+    for w in m
+        .teams
+        .iter()
+        .flat_map(|t| &t.players)
+        .flat_map(|p| &p.weapons)
+    {
+        let name = w.name.to_uppercase();
+        if opts.excluded_weapons.contains(&name) {
+            continue;
+        }
+        let total = totals.entry(name.clone()).or_insert_with(|| Weapon {
+            name,
+            ..Default::default()
+        });
+        total.hits = total.hits.saturating_add(w.hits);
+        total.shots = total.shots.saturating_add(w.shots);
+        total.kills = total.kills.saturating_add(w.kills);
+    }
+    let mut totals: Vec<Weapon> = totals.into_values().collect();
+    totals.sort_by_key(|w| std::cmp::Reverse(w.kills));
+    totals
+}
+
+fn format_weapon_meta(totals: &[Weapon], fmt: &StatFormatter) -> String {
+    if totals.is_empty() {
+        return String::new();
+    }
+    let mut output = String::from("*Weapon meta*\n");
+    for w in totals {
+        if let Some((_, emoji)) = WEAPON_EMOJIS.iter().find(|(abbr, _)| *abbr == w.name) {
+            output.push_str(&format!("{} ", emoji));
+        }
+        let mut line = format!(
+            "{}: {} kills match-wide",
+            w.name,
+            fmt.integer(w.kills.into())
+        );
+        if let Some(accuracy) = w.accuracy().filter(|_| w.shots > 0) {
+            line.push_str(&format!(", {} accuracy", fmt.percent(accuracy)));
+        }
+        output.push_str(&escape_markdown(&line));
+        output.push('\n');
+    }
+    output
+}
+
+/// Stat names longer than this are cut short in the two-column layout.
+const MAX_COLUMN_NAME: usize = 12;
+
+/// Packs `name: value` pairs two to a line, the left column padded so the
+/// right one lines up.
+fn two_column_stats(stats: &[(&str, &str)]) -> String {
+    let cells: Vec<String> = stats
+        .iter()
+        .map(|(name, val)| format!("{}: {val}", truncate(name, MAX_COLUMN_NAME)))
+        .collect();
+    let width = cells
+        .iter()
+        .step_by(2)
+        .map(|c| c.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut output = String::new();
+    for row in cells.chunks(2) {
+        if let [left, right] = row {
+            output.push_str(&format!("{left:<width$}  {right}\n"));
+        } else {
+            output.push_str(&format!("{}\n", row[0]));
+        }
+    }
+    output
+}
+
+fn format_player_block(
+    player: &Player,
+    opts: &ReportOptions,
+    frag_share: Option<u32>,
+    legend: &mut Legend,
+    low_sample: &mut bool,
+) -> String {
+    let mut output = String::new();
+    let title = opts
+        .accuracy_titles
+        .as_deref()
+        .zip(player.shown_accuracy())
+        .map(|(brackets, pct)| accuracy_title(pct, brackets))
+        .filter(|title| !title.is_empty())
+        .map(|title| format!(" \\({}\\)", escape_markdown(title)))
+        .unwrap_or_default();
+    output.push_str(&format!(
+        "```\nPlayer: {}{}\n",
+        opts.display_name(&player.name),
+        title
+    ));
+
+    let damage = player.damage_balance();
+    // mods may pool accuracy differently, so their own figure wins
+    let reported_accuracy = player.reported_accuracy();
+    let play_time = Some(opts.play_time_stat.as_str())
+        .filter(|name| !name.is_empty())
+        .and_then(|name| player.stat(name))
+        .filter(|secs| duration_secs(secs).is_some());
+    let mut stats = Vec::new();
+    for (stat_name, stat_val) in &player.stats {
+        if damage.is_some() && (stat_name == "DamageGiven" || stat_name == "DamageTaken") {
+            continue;
+        }
+        if reported_accuracy.is_some() && stat_name == "Accuracy" {
+            continue;
+        }
+        if opts.hidden_stats.contains(stat_name) {
+            continue;
+        }
+        if play_time.is_some() && *stat_name == opts.play_time_stat {
+            continue;
+        }
+        stats.push((stat_name.as_str(), stat_val.as_str()));
+    }
+    if opts.two_column_stats {
+        output.push_str(&escape_markdown(&two_column_stats(&stats)));
+    } else {
+        for (stat_name, stat_val) in stats {
+            output.push_str(&format!(
+                "{}: {}\n",
+                escape_markdown(stat_name),
+                escape_markdown(stat_val)
+            ));
+        }
+    }
+
+    if let Some(secs) = play_time {
+        output.push_str(&format!(
+            "Played: {}\n",
+            escape_markdown(&humanize_duration(secs))
+        ));
+    }
+    if let Some(kd) = player.kd_ratio() {
+        legend.note("K/D", "kills per death");
+        output.push_str(&format!(
+            "K/D: {}\n",
+            escape_markdown(&opts.formatter.ratio(kd))
+        ));
+    }
+    if let Some(share) = frag_share {
+        output.push_str(&format!(
+            "Frag share: {}\n",
+            escape_markdown(&opts.formatter.percent(share))
+        ));
+    }
+    if let Some((given, taken, net)) = damage {
+        let fmt = &opts.formatter;
+        let net = if net > 0 {
+            format!("+{}", fmt.integer(net.unsigned_abs()))
+        } else if net < 0 {
+            format!("-{}", fmt.integer(net.unsigned_abs()))
+        } else {
+            "0".to_string()
+        };
+        output.push_str(&format!(
+            "Dmg: {} given / {} taken \\(net {}\\)\n",
+            escape_markdown(&fmt.integer(given.unsigned_abs())),
+            escape_markdown(&fmt.integer(taken.unsigned_abs())),
+            escape_markdown(&net)
+        ));
+    }
+
+    let mut weapons = player.weapons.clone();
+    if opts.canonical_weapon_order {
+        sort_weapons(&mut weapons, &CANONICAL_WEAPON_ORDER);
+    }
+    let weapons: Vec<&Weapon> = weapons
+        .iter()
+        .filter(|w| !opts.weapons_with_kills_only || w.kills > 0)
+        .filter(|w| !opts.excluded_weapons.contains(&w.name.to_uppercase()))
+        .collect();
+    if !weapons.is_empty() {
+        output.push_str("Weapons: \n");
+        legend.note("Acc.", "hit accuracy");
+        for w in weapons {
+            legend.note_weapon(&w.name);
+            let fmt = &opts.formatter;
+            let accuracy = match w.accuracy() {
+                Some(pct) if opts.min_meaningful_shots.is_some_and(|min| w.shots < min) => {
+                    *low_sample = true;
+                    format!("{}*", fmt.percent(pct))
+                }
+                Some(pct) => fmt.percent(pct),
+                None => "N/A".to_string(),
+            };
+            let badge = if is_sharpshooter(w, opts) {
+                " 🎯"
+            } else {
+                ""
+            };
+            output.push_str(&format!(
+                "{}: Shots: {} \\| Acc. {} \\| Kills: {}{}\n",
+                escape_markdown(&w.name),
+                escape_markdown(&fmt.integer(w.shots.into())),
+                escape_markdown(&accuracy),
+                escape_markdown(&fmt.integer(w.kills.into())),
+                badge
+            ));
+        }
+    }
+    // after the weapons it sums up
+    if let Some(accuracy) = reported_accuracy.or_else(|| player.overall_accuracy()) {
+        legend.note("Acc.", "hit accuracy");
+        output.push_str(&format!(
+            "Overall Acc: {}\n",
+            escape_markdown(&opts.formatter.percent(accuracy))
+        ));
+    }
+    if !player.deaths_by_weapon.is_empty() {
+        let killed_by: Vec<String> = player
+            .deaths_by_weapon
+            .iter()
+            .map(|(weapon, count)| format!("{} x{}", weapon, count))
+            .collect();
+        output.push_str(&format!(
+            "Killed by: {}\n",
+            escape_markdown(&killed_by.join(", "))
+        ));
+    }
+    output.push_str("```\n");
+    output
+}
+
+/// Stats that carry no information in this match: zero for every player
+/// that has them, or (with several players) the same value for all of them.
+fn uniform_stats(m: &Match) -> HashSet<String> {
+    let players: Vec<&Player> = m.teams.iter().flat_map(|t| &t.players).collect();
+    let names: HashSet<&str> = players
+        .iter()
+        .flat_map(|p| p.stats.iter().map(|(n, _)| n.as_str()))
+        .collect();
+    names
+        .into_iter()
+        .filter(|name| {
+            let values: Vec<Option<&str>> = players.iter().map(|p| p.stat(name)).collect();
+            let all_zero = values
+                .iter()
+                .flatten()
+                .all(|v| parse_number(v) == Some(0.0));
+            let all_same = players.len() > 1 && values.iter().all(|v| *v == values[0]);
+            all_zero || all_same
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+fn format_match_report(m: &Match, opts: &ReportOptions) -> String {
+    format_match_sections(m, opts).concat()
+}
+
+/// The report split into the header (with the FFA scoreboard) and one
+/// section per team; the footer goes at the end of the last section.
+fn format_match_sections(m: &Match, opts: &ReportOptions) -> Vec<String> {
+    let suppressed;
+    let opts = if opts.auto_suppress_uniform {
+        suppressed = ReportOptions {
+            hidden_stats: uniform_stats(m),
+            ..opts.clone()
+        };
+        &suppressed
+    } else {
+        opts
+    };
+    let mut output = String::new();
+    let mut legend = Legend::default();
+    let mut low_sample = false;
+    if let Some(emoji) = opts
+        .type_emojis
+        .get(&m.match_type.to_uppercase())
+        .or(opts.default_type_emoji.as_ref())
+    {
+        output.push_str(&format!("{} ", escape_markdown(emoji)));
+    }
+    output.push_str("*Match concluded*\n");
+    let outcome = match_outcome(m);
+    if outcome == Outcome::Draw {
+        output.push_str("🤝 Drawn match\n");
+    }
+    if let Some(idx) = opts.watch_team.as_deref().and_then(|t| m.find_team(t)) {
+        // without numeric scores there's no result to announce
+        let banner = match outcome {
+            Outcome::Win(winner) if winner == idx => Some("WIN"),
+            Outcome::Win(_) => Some("LOSS"),
+            Outcome::Draw => Some("DRAW"),
+            Outcome::Unknown => None,
+        };
+        if let Some(banner) = banner {
+            output.push_str(&format!(
+                "*{}* for {}\n",
+                banner,
+                escape_markdown(&m.teams[idx].name)
+            ));
+        }
+    }
+    let match_type = match &m.ruleset {
+        Some(ruleset) => format!("{} ({})", m.match_type, capitalize(ruleset)),
+        None => m.match_type.clone(),
+    };
+    output.push_str(&format!(
+        "Map: {} \\| Type: {} \\| Duration: {}\n",
+        escape_markdown(&m.map),
+        escape_markdown(&match_type),
+        escape_markdown(&format_duration(&m.duration))
+    ));
+    if !m.server.is_empty() {
+        output.push_str(&format!("Server: {}\n", escape_markdown(&m.server)));
+    }
+    if let Some(reason) = m.end_reason() {
+        output.push_str(&format!("Ended: {}\n", reason));
+    }
+    if let Some(played_at) = m.played_at() {
+        let played_at = match opts.timezone {
+            Some(tz) => played_at.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z"),
+            None => played_at.format("%Y-%m-%d %H:%M UTC"),
+        };
+        output.push_str(&format!(
+            "Match time: {}\n",
+            escape_markdown(&played_at.to_string())
+        ));
+    }
+    if let Some(intensity) = m.intensity() {
+        output.push_str(&format!("Intensity: {}/100\n", intensity));
+    }
+    if let Some(mvp) = m.mvp().filter(|_| opts.mvp) {
+        output.push_str(&format!(
+            "MVP: {}\n",
+            escape_markdown(&opts.display_name(&mvp.name))
+        ));
+    }
+    output.push('\n');
+
+    let team_kills = |t: &Team| t.players.iter().map(Player::kills).sum::<u32>();
+    let match_kills: u32 = m.teams.iter().map(team_kills).sum();
+    let mut teams: Vec<&Team> = m.teams.iter().collect();
+    if m.is_ffa() {
+        // stable sort keeps XML order between equal (or unparsable) scores
+        teams.sort_by(|a, b| ffa_order(a, b));
+        output.push_str(&format_ffa_scoreboard(&teams, opts));
+        if let Some((winner, runner_up)) = opts
+            .photo_finish
+            .and_then(|margin| photo_finish(&teams, margin))
+        {
+            output.push_str(&format!(
+                "😤 Photo finish: {} edged {}\n",
+                escape_markdown(&opts.display_name(winner)),
+                escape_markdown(&opts.display_name(runner_up))
+            ));
+        }
+    }
+
+    let mut sections = vec![std::mem::take(&mut output)];
+    // markers only make sense between two sides
+    let outcome = if m.teams.len() == 2 {
+        outcome
+    } else {
+        Outcome::Unknown
+    };
+    for (i, team) in teams.into_iter().enumerate() {
+        if m.is_team_game {
+            let team_label = if i == 0 { "Team One" } else { "Team Two" };
+            let plain = opts.format == ReportFormat::Plain;
+            let marker = match outcome {
+                Outcome::Win(winner) if winner == i && plain => " \\(WINNER\\)",
+                Outcome::Win(winner) if winner == i => " 🏆",
+                Outcome::Draw => " \\(DRAW\\)",
+                _ => "",
+            };
+            if let Some(emoji) = opts.team_emojis.get(&team.color.to_lowercase()) {
+                output.push_str(&format!("{} ", escape_markdown(emoji)));
+            }
+            output.push_str(&format!(
+                "*{}*{}: *{}*\n",
+                team_label,
+                marker,
+                escape_markdown(&team.score)
+            ));
+            if let Some(stat) = opts.team_accuracy_stat {
+                if let Some(accuracy) = team.accuracy(stat) {
+                    let label = match stat {
+                        TeamAccuracyStat::Mean => "mean",
+                        TeamAccuracyStat::Median => "median",
+                    };
+                    output.push_str(&format!(
+                        "Team accuracy: {} \\({}\\)\n",
+                        escape_markdown(&opts.formatter.percent(accuracy.round() as u32)),
+                        label
+                    ));
+                }
+            }
+        }
+
+        let frag_total = if m.is_ffa() {
+            match_kills
+        } else {
+            team_kills(team)
+        };
+        // best score first; stable, so XML order breaks ties
+        let mut players: Vec<&Player> = team.players.iter().collect();
+        players.sort_by(|a, b| {
+            let score = |p: &Player| p.score().unwrap_or(f64::NEG_INFINITY);
+            score(b).total_cmp(&score(a))
+        });
+        for player in players {
+            let share = opts
+                .frag_share
+                .then(|| frag_share(player.kills(), frag_total))
+                .flatten();
+            output.push_str(&format_player_block(
+                player,
+                opts,
+                share,
+                &mut legend,
+                &mut low_sample,
+            ));
+        }
+        sections.push(std::mem::take(&mut output));
+    }
+
+    if opts.weapon_meta {
+        output.push_str(&format_weapon_meta(&weapon_meta(m, opts), &opts.formatter));
+    }
+
+    if let (true, Some(min)) = (low_sample, opts.min_meaningful_shots) {
+        output.push_str(&escape_markdown(&format!(
+            "* fewer than {} shots, accuracy is not meaningful\n",
+            min
+        )));
+    }
+
+    if opts.legend {
+        if let Some(footer) = legend.render() {
+            output.push_str(&footer);
+        }
+    }
+
+    let last = sections.len() - 1;
+    sections[last].push_str(&output);
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+        assert_eq!(result.map, "q3dm6");
+        assert_eq!(result.match_type, "TDM");
+        assert_eq!(result.duration, "601");
+        assert_eq!(result.teams.len(), 2);
+
+        // Team One (Score 5)
+        assert_eq!(result.teams[0].score, "5");
+        assert_eq!(result.teams[0].players.len(), 1);
         assert_eq!(result.teams[0].players[0].name, "Player1");
         assert_eq!(result.teams[0].players[0].weapons.len(), 2);
 
-        // MG Accuracy: 13/29 = 44%
-        let mg = &result.teams[0].players[0].weapons[0];
-        assert_eq!(mg.name, "MG");
-        assert_eq!(mg.hits, 13);
-        assert_eq!(mg.shots, 29);
-        let mg_acc = (mg.hits * 100) / mg.shots;
-        assert_eq!(mg_acc, 44);
+        // MG Accuracy: 13/29 = 44.8%, rounded to 45%
+        let mg = &result.teams[0].players[0].weapons[0];
+        assert_eq!(mg.name, "MG");
+        assert_eq!(mg.hits, 13);
+        assert_eq!(mg.shots, 29);
+        assert_eq!(mg.accuracy(), Some(45));
+
+        // Team Two (Score 0)
+        assert_eq!(result.teams[1].score, "0");
+        assert_eq!(result.teams[1].players.len(), 2);
+        assert_eq!(result.teams[1].players[0].name, "Player2");
+        assert_eq!(result.teams[1].players[1].name, "Player3");
+
+        let report = format_match_report(&result, &ReportOptions::default());
+        assert!(report.contains("Duration: 10:01"));
+    }
+
+    #[test]
+    fn test_server_in_header() {
+        let xml = r#"<match map="q3dm17" type="FFA" server="^1Frag^7Fest #1" duration="300">
+<player name="Solo"><stat name="Score" value="3"/></player>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(m.server, "FragFest #1");
+        let report = format_match_report(&m, &ReportOptions::default());
+        assert!(report.contains("Duration: 5:00\nServer: FragFest \\#1\n"));
+
+        let xml =
+            r#"<match map="q3dm17" type="FFA" hostname="q3.example.org" duration="300"></match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(m.server, "q3.example.org");
+
+        let xml = std::fs::read_to_string("test.xml").unwrap();
+        let m = parse_content(xml, &ParseOptions::default()).unwrap();
+        assert!(!format_match_report(&m, &ReportOptions::default()).contains("Server:"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_several_matches_in_one_file() {
+        let xml = r#"<matches>
+<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<teams score="0"><team name="Red" score="3"><player name="A"><stat name="Score" value="3"/></player></team>
+<team name="Blue"><player name="B"><stat name="Score" value="1"/></player></team></teams>
+</match>
+<match map="q3dm17" type="FFA" duration="300">
+<player name="C"><stat name="Score" value="9"/></player>
+</match>
+</matches>"#;
+        let matches = parse_all_matches(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].map, "q3dm6");
+        assert_eq!(matches[0].teams.len(), 2);
+        assert_eq!(matches[0].teams[1].score, "0");
+        assert_eq!(matches[1].map, "q3dm17");
+        assert!(!matches[1].is_team_game);
+        assert_eq!(matches[1].teams.len(), 1);
+        assert_eq!(matches[1].teams[0].players[0].name, "C");
+
+        let first = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(first.map, "q3dm6");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("batch.xml");
+        std::fs::write(&path, xml).unwrap();
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        reporter.process_file(&path).await;
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].1.contains("Map: q3dm6 "));
+        assert!(sent[1].1.contains("Map: q3dm17 "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_map_record_banner() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        reporter.db = Some(MatchDb::open(&dir.path().join("matches.db")).unwrap());
+        for (i, score) in ["12", "20", "15"].into_iter().enumerate() {
+            let path = dir.path().join(format!("match{i}.xml"));
+            std::fs::write(
+                &path,
+                format!(
+                    r#"<match map="q3dm6" type="FFA" duration="300">
+<player name="Solo"><stat name="Score" value="{score}"/></player>
+</match>"#
+                ),
+            )
+            .unwrap();
+            reporter.process_file(&path).await;
+        }
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        // the first match on a map has nothing to beat
+        assert!(!sent[0].1.contains("record"));
+        assert!(sent[1].1.starts_with("🏆 New q3dm6 record\\!\n"));
+        assert!(!sent[2].1.contains("record"));
+    }
+
+    #[test]
+    fn test_ruleset_in_header() {
+        let xml = r#"<match map="q3dm17" type="TDM" ruleset="instagib" duration="300">
+<player name="Solo"><stat name="Score" value="3"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(result.ruleset.as_deref(), Some("instagib"));
+
+        let report = format_match_report(&result, &ReportOptions::default());
+        assert!(report.contains(r"Type: TDM \(Instagib\)"));
+
+        let plain = parse_content(
+            xml.replace(r#" ruleset="instagib""#, ""),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(plain.ruleset, None);
+        assert!(format_match_report(&plain, &ReportOptions::default()).contains(r"Type: TDM \|"));
+    }
+
+    #[test]
+    fn test_weapons_with_kills_only() {
+        let m = Match {
+            map: "q3dm6".to_string(),
+            teams: vec![Team {
+                players: vec![Player {
+                    name: "Player1".to_string(),
+                    weapons: vec![
+                        Weapon {
+                            name: "MG".to_string(),
+                            hits: 40,
+                            shots: 120,
+                            kills: 0,
+                        },
+                        Weapon {
+                            name: "RL".to_string(),
+                            hits: 9,
+                            shots: 15,
+                            kills: 5,
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let full = format_match_report(&m, &ReportOptions::default());
+        assert!(full.contains("MG: Shots: 120"));
+
+        let opts = ReportOptions {
+            weapons_with_kills_only: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&m, &opts);
+        assert!(!report.contains("MG:"));
+        assert!(report.contains(r"RL: Shots: 15 \| Acc. 60% \| Kills: 5"));
+    }
+
+    #[test]
+    fn test_player_history_appends_per_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+
+        append_player_history(dir.path(), &result).unwrap();
+        append_player_history(dir.path(), &result).unwrap();
+
+        let history = std::fs::read_to_string(dir.path().join("player1.jsonl")).unwrap();
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry["map"], "q3dm6");
+        assert_eq!(entry["stats"]["Kills"], "5");
+        assert_eq!(entry["weapons"][1]["name"], "RL");
+
+        assert_eq!(history_file_name("../Evil/Name"), "___evil_name");
+        assert_eq!(history_file_name("  "), "unnamed");
+    }
+
+    #[test]
+    fn test_match_intensity() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team score="11">
+<player name="A"><stat name="Kills" value="14"/></player>
+<player name="B"><stat name="Kills" value="6"/></player>
+</team>
+<team score="9">
+<player name="C"><stat name="Kills" value="10"/></player>
+</team>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        // 30 kills in 10 minutes -> pace 1.0; 11 vs 9 -> closeness 0.9
+        let intensity = result.intensity().unwrap();
+        assert_eq!(intensity, 96);
+        assert!(intensity <= 100);
+        assert!(
+            format_match_report(&result, &ReportOptions::default()).contains("Intensity: 96/100")
+        );
+
+        let unknown = Match {
+            duration: String::new(),
+            ..Default::default()
+        };
+        assert_eq!(unknown.intensity(), None);
+    }
+
+    #[test]
+    fn test_kv_output() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            kv: true,
+            ..Default::default()
+        };
+        assert_eq!(opts.parse_mode(), None);
+
+        let kv = render_report(&result, &opts).text;
+        let lines: Vec<&str> = kv.lines().collect();
+        assert_eq!(lines[0], "map=q3dm6");
+        assert!(lines.contains(&"type=TDM"));
+        assert!(lines.contains(&"team1_score=5"));
+        assert!(lines.contains(&"team1_player1_name=Player1"));
+        assert!(lines.contains(&"team1_player1_kills=5"));
+        assert!(lines.contains(&"team1_player1_rl_kills=3"));
+        assert!(lines.contains(&"team2_player2_name=Player3"));
+
+        assert_eq!(kv_value("two\nlines"), r#""two\nlines""#);
+        assert_eq!(kv_value(""), "\"\"");
+        assert_eq!(kv_value("a b"), "a b");
+    }
+
+    #[test]
+    fn test_weapon_accuracy_rounds() {
+        let weapon = |hits, shots| Weapon {
+            name: "RG".to_string(),
+            hits,
+            shots,
+            kills: 0,
+        };
+        assert_eq!(weapon(1, 3).accuracy(), Some(33));
+        assert_eq!(weapon(2, 3).accuracy(), Some(67));
+        assert_eq!(weapon(1, 2).accuracy(), Some(50));
+        assert_eq!(weapon(5, 3).accuracy(), Some(100));
+        assert_eq!(weapon(0, 0).accuracy(), Some(0));
+        assert_eq!(weapon(0, 7).accuracy(), Some(0));
+
+        // hits * 100 doesn't fit in a u32 here
+        let huge = u32::MAX / 100 + 1;
+        assert_eq!(weapon(huge, huge * 2).accuracy(), Some(50));
+        assert_eq!(weapon(huge, u32::MAX).accuracy(), Some(1));
+        assert_eq!(weapon(u32::MAX - 1, u32::MAX).accuracy(), Some(100));
+    }
+
+    #[test]
+    fn test_hits_without_shots() {
+        let xml = r#"<match map="q3dm6" type="FFA" duration="300">
+<player name="Glitch"><weapon name="RG" hits="5" shots="0" kills="2"/></player>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let rg = &m.teams[0].players[0].weapons[0];
+        assert_eq!(rg.accuracy(), None);
+
+        let opts = ReportOptions {
+            sharpshooter: Some(default_sharpshooter_thresholds()),
+            min_meaningful_shots: Some(10),
+            ..Default::default()
+        };
+        assert!(!is_sharpshooter(rg, &opts));
+        let report = format_match_report(&m, &opts);
+        assert!(report.contains("RG: Shots: 0 \\| Acc. N/A \\| Kills: 2\n"));
+        assert!(!report.contains("fewer than"));
+    }
+
+    #[test]
+    fn test_accuracy_titles() {
+        let brackets = default_accuracy_brackets();
+        assert_eq!(accuracy_title(45, &brackets), "Sharp");
+        assert_eq!(accuracy_title(0, &brackets), "Spray");
+        assert_eq!(accuracy_title(60, &brackets), "Deadeye");
+
+        let args = Args::parse_from([
+            "q3-stats-bot",
+            "--accuracy-titles",
+            "--accuracy-bracket",
+            "75=Railgod,10%=Warming up",
+        ]);
+        let opts = ReportOptions::from_args(&args);
+        let custom = opts.accuracy_titles.as_deref().unwrap();
+        assert_eq!(accuracy_title(5, custom), "Warming up");
+        assert_eq!(accuracy_title(80, custom), "Railgod");
+
+        let xml = r#"<match map="q3dm6" type="FFA" duration="300">
+<player name="Aim"><weapon name="RG" hits="9" shots="20" kills="3"/></player>
+<player name="Idle"><stat name="Score" value="0"/></player>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let args = Args::parse_from(["q3-stats-bot", "--accuracy-titles"]);
+        let report = format_match_report(&m, &ReportOptions::from_args(&args));
+        assert!(report.contains("Player: Aim \\(Sharp\\)\n"));
+        assert!(report.contains("Player: Idle\n"));
+    }
+
+    #[test]
+    fn test_sharpshooter_by_weapon_class() {
+        let weapon = |name: &str| Weapon {
+            name: name.to_string(),
+            hits: 40,
+            shots: 100,
+            kills: 3,
+        };
+        let opts = ReportOptions {
+            sharpshooter: Some(default_sharpshooter_thresholds()),
+            ..Default::default()
+        };
+        assert!(is_sharpshooter(&weapon("RL"), &opts));
+        assert!(!is_sharpshooter(&weapon("LG"), &opts));
+        assert!(!is_sharpshooter(&weapon("RL"), &ReportOptions::default()));
+
+        let mut strict = default_sharpshooter_thresholds();
+        strict.insert("RL".to_string(), 45);
+        let opts = ReportOptions {
+            sharpshooter: Some(strict),
+            ..Default::default()
+        };
+        assert!(!is_sharpshooter(&weapon("RL"), &opts));
+        assert_eq!(parse_weapon_threshold("lg=30%"), Ok(("LG".to_string(), 30)));
+    }
+
+    #[test]
+    fn test_low_sample_accuracy_is_starred() {
+        let m = Match {
+            teams: vec![Team {
+                players: vec![Player {
+                    name: "Player1".to_string(),
+                    weapons: vec![
+                        Weapon {
+                            name: "LG".to_string(),
+                            hits: 1,
+                            shots: 1,
+                            kills: 0,
+                        },
+                        Weapon {
+                            name: "MG".to_string(),
+                            hits: 13,
+                            shots: 29,
+                            kills: 2,
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let opts = ReportOptions {
+            min_meaningful_shots: Some(10),
+            ..Default::default()
+        };
+        let report = format_match_report(&m, &opts);
+        assert!(report.contains(r"LG: Shots: 1 \| Acc. 100%\* \|"));
+        assert!(report.contains(r"MG: Shots: 29 \| Acc. 45% \|"));
+        assert!(report.contains(r"\* fewer than 10 shots"));
+
+        let plain = format_match_report(&m, &ReportOptions::default());
+        assert!(!plain.contains(r"\*"));
+    }
+
+    #[test]
+    fn test_damage_balance_replaces_raw_stats() {
+        let player = Player {
+            name: "Player1".to_string(),
+            stats: vec![
+                ("Kills".to_string(), "5".to_string()),
+                ("DamageGiven".to_string(), "1200".to_string()),
+                ("DamageTaken".to_string(), "800".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(player.damage_balance(), Some((1200, 800, 400)));
+
+        let block = format_player_block(
+            &player,
+            &ReportOptions::default(),
+            None,
+            &mut Legend::default(),
+            &mut false,
+        );
+        assert!(block.contains(r"Dmg: 1200 given / 800 taken \(net \+400\)"));
+        assert!(!block.contains("DamageGiven"));
+        assert!(block.contains("Kills: 5"));
+
+        let given_only = Player {
+            stats: vec![("DamageGiven".to_string(), "1200".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(given_only.damage_balance(), None);
+
+        let floats = Player {
+            stats: vec![
+                ("DamageGiven".to_string(), "1200.0".to_string()),
+                ("DamageTaken".to_string(), " 799.6 ".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(floats.damage_balance(), Some((1200, 800, 400)));
+    }
+
+    #[test]
+    fn test_canonical_weapon_order() {
+        let mut weapons: Vec<Weapon> = ["LG", "XW", "RL", "AB", "mg"]
+            .iter()
+            .map(|name| Weapon {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        sort_weapons(&mut weapons, &CANONICAL_WEAPON_ORDER);
+        let names: Vec<&str> = weapons.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, ["mg", "RL", "LG", "AB", "XW"]);
+    }
+
+    #[test]
+    fn test_kd_ratio() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+        let report = format_match_report(&result, &ReportOptions::default());
+        for player in result.teams.iter().flat_map(|t| &t.players) {
+            let kills: f32 = player.stat("Kills").unwrap().parse().unwrap();
+            let deaths: f32 = player.stat("Deaths").unwrap().parse().unwrap();
+            let expected = format!("{:.2}", kills / deaths.max(1.0)).replace('.', r"\.");
+            let block = report
+                .split("Player: ")
+                .find(|b| b.starts_with(&player.name))
+                .unwrap();
+            assert!(block.contains(&format!("K/D: {}\n", expected)), "{block}");
+        }
+        assert!(report.contains("Player: Player1\n") && report.contains("K/D: 5\\.00\n"));
+
+        let mut player = Player::default();
+        player.stats.push(("Kills".to_string(), "7".to_string()));
+        player.stats.push(("Deaths".to_string(), "n/a".to_string()));
+        assert_eq!(player.kd_ratio(), None);
+
+        // float-formatted and padded, as some mods write them
+        let player = Player {
+            stats: vec![
+                ("Kills".to_string(), "7.0".to_string()),
+                ("Deaths".to_string(), " 2 ".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(player.kd_ratio(), Some(3.5));
+    }
+
+    #[test]
+    fn test_plain_format() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="[*] Mr.T"><stat name="Kills" value="9"/><stat name="Deaths" value="4"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let args = Args::parse_from(["q3-stats-bot", "--format", "plain"]);
+        let opts = ReportOptions::from_args(&args);
+        assert_eq!(opts.parse_mode(), None);
+
+        let plain = render_report(&result, &opts).text;
+        assert!(plain.contains("Map: q3dm6 | Type: 1v1"));
+        assert!(plain.contains("\nPlayer: [*] Mr.T\nKills: 9\n"));
+        assert!(plain.contains("K/D: 2.25\n"));
+        assert!(!plain.contains(['\\', '`']));
+        assert_eq!(
+            render_outgoing(&result, &opts).text,
+            format_match_report(&result, &opts)
+        );
+
+        let xml = r#"<match map="q3_dm6" type="1v1" duration="600">
+<player name="Mr_T"><stat name="Kills" value="9"/><stat name="Deaths" value="4"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let args = Args::parse_from(["q3-stats-bot", "--format", "plain", "--legend"]);
+        let plain = render_report(&result, &ReportOptions::from_args(&args)).text;
+        assert!(plain.contains("\nLegend: "));
+        assert!(!plain.contains("_Legend"));
+        // escaped or inside a code block, an underscore is kept
+        assert!(plain.contains("Map: q3_dm6 "));
+        assert!(plain.contains("Player: Mr_T\n"));
+    }
+
+    #[test]
+    fn test_max_name_length() {
+        let name = "x".repeat(40);
+        let xml = format!(
+            r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="{name}"><stat name="Score" value="5"/></player>
+</match>"#
+        );
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            max_name_length: Some(16),
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        assert!(report.contains(&format!("Player: {}…\n", "x".repeat(15))));
+        assert!(!report.contains(&name));
+        assert_eq!(result.teams[0].players[0].name, name);
+
+        assert_eq!(truncate("short", 16), "short");
+    }
+
+    #[test]
+    fn test_strip_clan_tags() {
+        assert_eq!(clan_tag("KDZ:VaNeZzz"), Some(("KDZ", "VaNeZzz")));
+        assert_eq!(clan_tag("[KDZ] VaNeZzz"), Some(("KDZ", "VaNeZzz")));
+        assert_eq!(clan_tag("(uF)|Doom"), Some(("uF", "Doom")));
+        assert_eq!(clan_tag("Anarki"), None);
+        assert_eq!(clan_tag("Ex:"), None);
+        assert_eq!(clan_tag("[]Bones"), None);
+        assert_eq!(clan_tag("Too Long Tag:Crash"), None);
+
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="^1KDZ^7:VaNeZzz"><stat name="Score" value="5"/></player>
+<player name="Anarki"><stat name="Score" value="3"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let args = Args::parse_from(["q3-stats-bot", "--strip-clan-tags"]);
+        let report = format_match_report(&result, &ReportOptions::from_args(&args));
+        assert!(report.contains("Player: VaNeZzz\n"));
+        assert!(report.contains("Player: Anarki\n"));
+        assert!(!report.contains("KDZ"));
+        assert_eq!(result.teams[0].players[0].name, "KDZ:VaNeZzz");
+
+        let report = format_match_report(&result, &ReportOptions::default());
+        assert!(report.contains("Player: KDZ:VaNeZzz\n"));
+    }
+
+    #[test]
+    fn test_api_url() {
+        let args = Args::parse_from(["q3-stats-bot", "--api-url", "http://localhost:8081/tg"]);
+        let bot = build_bot(Some("0:token".to_string()), args.api_url);
+        assert_eq!(bot.api_url().as_str(), "http://localhost:8081/tg/");
+
+        let bot = build_bot(Some("0:token".to_string()), None);
+        assert_eq!(bot.api_url().as_str(), "https://api.telegram.org/");
+
+        for bad in ["localhost:8081", "ftp://example.com", "not a url"] {
+            assert!(Args::try_parse_from(["q3-stats-bot", "--api-url", bad]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_players_sorted_by_score() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team name="Reds" score="30">
+<player name="Low"><stat name="Score" value="4"/></player>
+<player name="NoScore"><stat name="Kills" value="9"/></player>
+<player name="High"><stat name="Score" value=" 20 "/></player>
+<player name="Mid"><stat name="Score" value="11"/></player>
+</team>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let report = format_match_report(&result, &ReportOptions::default());
+        let order: Vec<&str> = report
+            .lines()
+            .filter_map(|l| l.strip_prefix("Player: "))
+            .collect();
+        assert_eq!(order, ["High", "Mid", "Low", "NoScore"]);
+    }
+
+    #[test]
+    fn test_weapon_meta() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team name="Reds" score="9"><player name="A">
+<weapon name="RL" hits="10" shots="40" kills="6"/>
+<weapon name="LG" hits="50" shots="100" kills="2"/>
+</player></team>
+<team name="Blues" score="7"><player name="B">
+<weapon name="rl" hits="10" shots="10" kills="5"/>
+</player></team>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let totals = weapon_meta(&result, &ReportOptions::default());
+        let kills: Vec<(&str, u32)> = totals.iter().map(|w| (w.name.as_str(), w.kills)).collect();
+        assert_eq!(kills, [("RL", 11), ("LG", 2)]);
+
+        let opts = ReportOptions {
+            weapon_meta: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        assert!(report.contains(
+            "*Weapon meta*\n🚀 RL: 11 kills match\\-wide, 40% accuracy\n⚡ LG: 2 kills match\\-wide"
+        ));
+    }
+
+    #[test]
+    fn test_two_column_stats() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Doom">
+<stat name="Kills" value="9"/>
+<stat name="Deaths" value="4"/>
+<stat name="SuicidesByLava" value="2"/>
+<stat name="Score" value="20"/>
+</player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            two_column_stats: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        assert!(report
+            .contains("Player: Doom\nKills: 9         Deaths: 4\nSuicidesByL…: 2  Score: 20\n"));
+
+        assert_eq!(
+            two_column_stats(&[("A", "1"), ("B", "2"), ("C", "3")]),
+            "A: 1  B: 2\nC: 3\n"
+        );
+    }
+
+    #[test]
+    fn test_frag_share_per_team() {
+        let player = |name: &str, kills: &str| Player {
+            name: name.to_string(),
+            stats: vec![("Kills".to_string(), kills.to_string())],
+            ..Default::default()
+        };
+        let m = Match {
+            is_team_game: true,
+            teams: vec![
+                Team {
+                    players: vec![player("A", "7"), player("B", "3")],
+                    ..Default::default()
+                },
+                Team {
+                    players: vec![player("C", "0")],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let opts = ReportOptions {
+            frag_share: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&m, &opts);
+        assert!(report.contains("Player: A\nKills: 7\nFrag share: 70%\n"));
+        assert!(report.contains("Player: B\nKills: 3\nFrag share: 30%\n"));
+        // nobody on the second team scored
+        assert!(report.contains("Player: C\nKills: 0\n```"));
+
+        assert!(!format_match_report(&m, &ReportOptions::default()).contains("Frag share"));
+    }
+
+    #[test]
+    fn test_compact_toggle_changes_report() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let m = parse_content(xml, &ParseOptions::default()).unwrap();
+        let mut opts = ReportOptions::default();
+        let full = render_outgoing(&m, &opts);
+        assert!(full.text.contains("Player: "));
+
+        *opts.feature("compact").unwrap() = true;
+        let compact = render_outgoing(&m, &opts);
+        assert_eq!(compact.text, format_match_summary(&m, &opts));
+        assert!(!compact.text.contains("Player: "));
+    }
+
+    #[test]
+    fn test_summary_with_document() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team name="Red" color="red" score="7"><player name="A"><stat name="Kills" value="7"/></player></team>
+<team name="Blue" color="blue" score="3"><player name="B"><stat name="Kills" value="3"/></player></team>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            summary_with_document: true,
+            ..ReportOptions::from_args(&Args::parse_from(["q3-stats-bot"]))
+        };
+
+        let out = render_outgoing(&m, &opts);
+        assert_eq!(
+            out.text,
+            "*TDM on q3dm6*, 10:00\n🔴 Red: *7* \\| 🔵 Blue: *3*\n"
+        );
+        let doc = out.document.expect("full report attached");
+        assert_eq!(doc.file_name, "q3dm6.txt");
+        assert!(doc.contents.starts_with("⚔️ Match concluded\n"));
+        assert!(doc.contents.contains("Player: A\nKills: 7\n"));
+        assert!(!doc.contents.contains(['\\', '*', '`']));
+
+        let plain = render_outgoing(&m, &ReportOptions::default());
+        assert_eq!(plain.document, None);
+        assert!(plain.text.starts_with("*Match concluded*"));
+    }
+
+    #[test]
+    fn test_rendered_report_follows_delivery() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let m = parse_content(xml, &ParseOptions::default()).unwrap();
+        let opts = |flags: &[&str]| {
+            let args = Args::parse_from(["q3-stats-bot"].iter().chain(flags));
+            ReportOptions::from_args(&args)
+        };
+
+        let compact = render_report(&m, &opts(&["--compact"]));
+        assert_eq!(compact, render_outgoing(&m, &opts(&["--compact"])));
+        assert!(compact.text.starts_with("*TDM on q3dm6*"));
+
+        let with_doc = render_report(&m, &opts(&["--summary-with-document"]));
+        assert_eq!(with_doc.text, compact.text);
+        assert!(with_doc.document.is_some());
+
+        let plain = render_report(&m, &opts(&["--compact", "--format", "plain"]));
+        assert!(plain.text.starts_with("TDM on q3dm6, "), "{}", plain.text);
+        assert!(!plain.text.contains(['\\', '*']));
+    }
+
+    #[test]
+    fn test_summary_names_unnamed_teams() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let m = parse_content(xml, &ParseOptions::default()).unwrap();
+        let summary = format_match_summary(&m, &ReportOptions::default());
+        assert_eq!(
+            summary.lines().nth(1),
+            Some("Team One: *5* \\| Team Two: *0*")
+        );
+    }
+
+    #[test]
+    fn test_wrapped_teams_and_players() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<teams score="0">
+<team name="Red" score="5"><players><player name="A"><stat name="Kills" value="1"/></player><player name="B"><stat name="Kills" value="1"/></player></players></team>
+<team name="Blue"><players><player name="C"><stat name="Kills" value="1"/></player></players></team>
+</teams>
+<players team="blue"><player name="D"><stat name="Kills" value="1"/></player></players>
+<players><player name="Spectator"><stat name="Kills" value="1"/></player></players>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let roster = |t: &Team| t.players.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        assert_eq!(m.teams.len(), 3);
+        assert_eq!(
+            (m.teams[0].name.as_str(), m.teams[0].score.as_str()),
+            ("Red", "5")
+        );
+        assert_eq!(roster(&m.teams[0]), ["A", "B"]);
+        assert_eq!(
+            (m.teams[1].name.as_str(), m.teams[1].score.as_str()),
+            ("Blue", "0")
+        );
+        assert_eq!(roster(&m.teams[1]), ["C", "D"]);
+        // an unlabelled wrapper is match-scoped, like a bare player
+        assert_eq!(roster(&m.teams[2]), ["Spectator"]);
+    }
+
+    #[test]
+    fn test_reported_accuracy_beats_pooled() {
+        let mut player = Player {
+            name: "Player1".to_string(),
+            weapons: vec![
+                Weapon {
+                    name: "MG".to_string(),
+                    hits: 13,
+                    shots: 29,
+                    kills: 2,
+                },
+                Weapon {
+                    name: "RL".to_string(),
+                    hits: 5,
+                    shots: 11,
+                    kills: 3,
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(player.overall_accuracy(), Some(45));
+        let block = |p: &Player| {
+            format_player_block(
+                p,
+                &ReportOptions::default(),
+                None,
+                &mut Legend::default(),
+                &mut false,
+            )
+        };
+        assert!(block(&player).contains("Overall Acc: 45%\n"));
+
+        player
+            .stats
+            .push(("Accuracy".to_string(), "55".to_string()));
+        let report = block(&player);
+        assert!(report.contains("Overall Acc: 55%\n"));
+        assert!(!report.contains("Accuracy: 55"));
+
+        assert_eq!(Player::default().overall_accuracy(), None);
+    }
+
+    #[test]
+    fn test_weapon_with_closing_tag() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="A"><weapon name="RL" hits="5" shots="11" kills="3"></weapon>
+<weapon name="RG" hits="7" shots="20" kills="4"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let weapons = &result.teams[0].players[0].weapons;
+        assert_eq!(weapons.len(), 2);
+        assert_eq!(
+            (
+                weapons[0].name.as_str(),
+                weapons[0].hits,
+                weapons[0].shots,
+                weapons[0].kills
+            ),
+            ("RL", 5, 11, 3)
+        );
+        assert_eq!(weapons[1].name, "RG");
+    }
+
+    #[test]
+    fn test_overall_accuracy_after_weapons() {
+        let weapon = |name: &str, hits, shots| Weapon {
+            name: name.to_string(),
+            hits,
+            shots,
+            kills: 1,
+        };
+        let player = Player {
+            name: "Player1".to_string(),
+            weapons: vec![weapon("G", 3, 0), weapon("RG", 7, 20), weapon("LG", 30, 70)],
+            ..Default::default()
+        };
+        // 37/90 = 41.1%; the gauntlet's hits without shots don't count
+        assert_eq!(player.overall_accuracy(), Some(41));
+        let block = format_player_block(
+            &player,
+            &ReportOptions::default(),
+            None,
+            &mut Legend::default(),
+            &mut false,
+        );
+        assert!(block.contains("LG: Shots: 70 \\| Acc. 43% \\| Kills: 1\nOverall Acc: 41%\n"));
+
+        // 2/3 rounds up, like a single weapon would
+        let player = Player {
+            weapons: vec![weapon("MG", 1, 2), weapon("SG", 1, 1)],
+            ..Default::default()
+        };
+        assert_eq!(player.overall_accuracy(), Some(67));
+    }
+
+    #[test]
+    fn test_auto_suppress_uniform_stats() {
+        let player = |name: &str, kills: &str| Player {
+            name: name.to_string(),
+            stats: vec![
+                ("Kills".to_string(), kills.to_string()),
+                ("Suicides".to_string(), "0".to_string()),
+                ("Team".to_string(), "1".to_string()),
+            ],
+            ..Default::default()
+        };
+        let m = Match {
+            teams: vec![Team {
+                players: vec![player("A", "7"), player("B", "3")],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            uniform_stats(&m),
+            HashSet::from(["Suicides".to_string(), "Team".to_string()])
+        );
+
+        let opts = ReportOptions {
+            auto_suppress_uniform: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&m, &opts);
+        assert!(report.contains("Player: A\nKills: 7\n```"));
+        assert!(!report.contains("Suicides"));
+        assert!(format_match_report(&m, &ReportOptions::default()).contains("Suicides: 0"));
+    }
+
+    #[test]
+    fn test_excluded_weapons_are_hidden() {
+        let player = Player {
+            name: "Player1".to_string(),
+            weapons: vec![
+                Weapon {
+                    name: "MG".to_string(),
+                    hits: 13,
+                    shots: 29,
+                    kills: 2,
+                },
+                Weapon {
+                    name: "RL".to_string(),
+                    hits: 5,
+                    shots: 11,
+                    kills: 3,
+                },
+            ],
+            ..Default::default()
+        };
+        let args = Args::parse_from(["q3-stats-bot", "--exclude-weapon", "mg"]);
+        let block = format_player_block(
+            &player,
+            &ReportOptions::from_args(&args),
+            None,
+            &mut Legend::default(),
+            &mut false,
+        );
+        assert!(!block.contains("MG:"));
+        assert!(block.contains(r"RL: Shots: 11 \| Acc. 45% \| Kills: 3"));
+        assert_eq!(player.weapons.len(), 2);
+    }
+
+    #[test]
+    fn test_player_play_time() {
+        let player = Player {
+            name: "Player1".to_string(),
+            stats: vec![
+                ("Kills".to_string(), "5".to_string()),
+                ("PlayTime".to_string(), "372".to_string()),
+            ],
+            ..Default::default()
+        };
+        let opts = ReportOptions::from_args(&Args::parse_from(["q3-stats-bot"]));
+        let block = format_player_block(&player, &opts, None, &mut Legend::default(), &mut false);
+        assert!(block.contains("Kills: 5\nPlayed: 6m 12s\n"));
+        assert!(!block.contains("PlayTime"));
+
+        let args = Args::parse_from(["q3-stats-bot", "--play-time-stat", "Time"]);
+        let block = format_player_block(
+            &player,
+            &ReportOptions::from_args(&args),
+            None,
+            &mut Legend::default(),
+            &mut false,
+        );
+        assert!(!block.contains("Played:"));
+        assert!(block.contains("PlayTime: 372"));
+    }
+
+    #[test]
+    fn test_header_emoji_by_match_type() {
+        let opts = ReportOptions::from_args(&Args::parse_from(["q3-stats-bot"]));
+        let header = |match_type: &str| {
+            let m = Match {
+                match_type: match_type.to_string(),
+                ..Default::default()
+            };
+            let report = format_match_report(&m, &opts);
+            report.lines().next().unwrap().to_string()
+        };
+        assert_eq!(header("CTF"), "🏁 *Match concluded*");
+        assert_eq!(header("1v1"), "🎯 *Match concluded*");
+        assert_eq!(header("Instagib"), "🎮 *Match concluded*");
+
+        let args = Args::parse_from(["q3-stats-bot", "--default-type-emoji", ""]);
+        let m = Match::default();
+        let report = format_match_report(&m, &ReportOptions::from_args(&args));
+        assert!(report.starts_with("*Match concluded*\n"));
+    }
+
+    #[test]
+    fn test_legend_lists_weapons_used() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+
+        let without = format_match_report(&result, &ReportOptions::default());
+        assert!(!without.contains("Legend"));
+
+        let opts = ReportOptions {
+            legend: true,
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        let footer = report.lines().last().unwrap();
+        assert!(footer.starts_with("_Legend:_"));
+        assert!(footer.contains(r"Acc\. \= hit accuracy"));
+        assert!(footer.contains(r"MG \= Machinegun"));
+        assert!(footer.contains(r"RL \= Rocket Launcher"));
+        assert!(!footer.contains("Railgun"));
+    }
+
+    #[test]
+    fn test_stat_value_fallbacks() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Modded">
+<stat name="Kills" val="9"/>
+<stat name="Deaths" amount="4"/>
+<stat name="Assists">3</stat>
+<stat name="Nothing"/>
+</player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let player = &result.teams[0].players[0];
+        assert_eq!(player.stat("Kills"), Some("9"));
+        assert_eq!(player.stat("Deaths"), Some("4"));
+        assert_eq!(player.stat("Assists"), Some("3"));
+        assert_eq!(player.stat("Nothing"), None);
+
+        let strict = ParseOptions {
+            stat_value_attrs: vec!["value".to_string()],
+            ..ParseOptions::default()
+        };
+        let result = parse_content(xml.to_string(), &strict).unwrap();
+        assert_eq!(result.teams[0].players[0].stat("Kills"), None);
+    }
+
+    #[test]
+    fn test_hide_and_show_stats() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Doom">
+<stat name="Kills" value="9"/>
+<stat name="Assists" value="3"/>
+<stat name="RA" value="5"/>
+<stat name="YA" value="2"/>
+</player>
+</match>"#;
+        let stat_names = |args: &[&str]| {
+            let args = Args::parse_from([&["q3-stats-bot"], args].concat());
+            let result = parse_content(xml.to_string(), &ParseOptions::from_args(&args)).unwrap();
+            let player = &result.teams[0].players[0];
+            player
+                .stats
+                .iter()
+                .map(|(n, _)| n.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(stat_names(&[]), ["Kills", "Assists"]);
+        assert_eq!(
+            stat_names(&["--hide-stats", "Assists"]),
+            ["Kills", "RA", "YA"]
+        );
+        assert_eq!(
+            stat_names(&["--show-stats", "RA"]),
+            ["Kills", "Assists", "RA"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_stat_policy() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Doom">
+<stat name="Kills" value="4"/>
+<stat name="Deaths" value="2"/>
+<stat name="Kills" value="5"/>
+</player>
+</match>"#;
+        let stats = |policy| {
+            let opts = ParseOptions {
+                duplicate_stat_policy: policy,
+                ..ParseOptions::default()
+            };
+            let result = parse_content(xml.to_string(), &opts).unwrap();
+            result.teams[0].players[0].stats.clone()
+        };
+        let pair = |n: &str, v: &str| (n.to_string(), v.to_string());
+        assert_eq!(
+            stats(DuplicateStatPolicy::First),
+            [pair("Kills", "4"), pair("Deaths", "2")]
+        );
+        assert_eq!(
+            stats(DuplicateStatPolicy::Last),
+            [pair("Kills", "5"), pair("Deaths", "2")]
+        );
+        assert_eq!(
+            stats(DuplicateStatPolicy::Sum),
+            [pair("Kills", "9"), pair("Deaths", "2")]
+        );
+
+        let mut player = Player {
+            stats: vec![pair("Note", "a"), pair("Note", "b")],
+            ..Default::default()
+        };
+        player.merge_duplicate_stats(DuplicateStatPolicy::Sum);
+        assert_eq!(player.stats, [pair("Note", "b")]);
+    }
+
+    #[test]
+    fn test_lenient_attribute_quotes() {
+        let xml = r#"<match map='q3dm17' type=FFA duration="300">
+<team><player name='Visor'><stat name=Score value='12'/></player></team>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(m.map, "q3dm17");
+        assert_eq!(m.match_type, "FFA");
+        assert_eq!(m.teams[0].players[0].name, "Visor");
+        assert_eq!(m.teams[0].players[0].stat("Score"), Some("12"));
+
+        let unclosed = r#"<match map="q3dm17 type="FFA"><team></team></match>"#;
+        let err = parse_content(unclosed.to_string(), &ParseOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("error at position"), "{}", err);
+    }
+
+    #[test]
+    fn test_player_aliases() {
+        let args = Args::parse_from([
+            "q3-stats-bot",
+            "--alias",
+            "oldnick=RealName",
+            "--alias",
+            "NewNick = RealName",
+        ]);
+        let opts = ParseOptions::from_args(&args);
+        assert_eq!(canonicalize_name("oldnick", &opts.aliases), "RealName");
+        assert_eq!(canonicalize_name("newnick", &opts.aliases), "RealName");
+        assert_eq!(canonicalize_name("Visor", &opts.aliases), "Visor");
+
+        let dir = tempfile::tempdir().unwrap();
+        for nick in ["^1old^7nick", "newnick"] {
+            let xml = format!(
+                r#"<match map="q3dm6" type="FFA" duration="300">
+<team><player name="{}"><stat name="Score" value="5"/></player></team>
+</match>"#,
+                nick
+            );
+            let m = parse_content(xml, &opts).unwrap();
+            assert_eq!(m.teams[0].players[0].name, "RealName");
+            append_player_history(dir.path(), &m).unwrap();
+        }
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let history = std::fs::read_to_string(dir.path().join("realname.jsonl")).unwrap();
+        assert_eq!(history.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_alternate_element_names() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Modder">
+<value name="Kills" value="9"/>
+<gun name="RL" hits="10" shots="20" kills="5"/>
+<gun name="LG" hits="30" shots="100" kills="4"/>
+</player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert!(result.teams[0].players[0].weapons.is_empty());
+
+        let modded = ParseOptions {
+            stat_elements: vec!["stat".to_string(), "value".to_string()],
+            weapon_elements: vec!["weapon".to_string(), "gun".to_string()],
+            ..ParseOptions::default()
+        };
+        let result = parse_content(xml.to_string(), &modded).unwrap();
+        let player = &result.teams[0].players[0];
+        assert_eq!(player.stat("Kills"), Some("9"));
+        let weapons: Vec<(&str, u32)> = player
+            .weapons
+            .iter()
+            .map(|w| (w.name.as_str(), w.kills))
+            .collect();
+        assert_eq!(weapons, [("RL", 5), ("LG", 4)]);
+    }
+
+    #[test]
+    fn test_deaths_by_weapon() {
+        let xml = r#"<match map="q3dm6" type="1v1" duration="600">
+<player name="Victim">
+<stat name="Deaths" value="6"/>
+<deaths><death weapon="RL" count="4"/><death weapon="LG" count="2"/></deaths>
+</player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let player = &result.teams[0].players[0];
+        assert_eq!(
+            player.deaths_by_weapon,
+            vec![("RL".to_string(), 4), ("LG".to_string(), 2)]
+        );
+        assert!(format_match_report(&result, &ReportOptions::default())
+            .contains("Killed by: RL x4, LG x2\n"));
+
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let plain = parse_content(xml, &ParseOptions::default()).unwrap();
+        assert!(!format_match_report(&plain, &ReportOptions::default()).contains("Killed by"));
+    }
+
+    #[test]
+    fn test_match_timestamp() {
+        let xml = r#"<match map="q3dm6" type="FFA" timestamp="1768765439" duration="600">
+<player name="Solo"><stat name="Score" value="3"/></player>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(m.timestamp, Some(1768765439));
+        assert!(format_match_report(&m, &ReportOptions::default())
+            .contains("Match time: 2026\\-01\\-18 19:43 UTC\n"));
+
+        let args = Args::parse_from(["q3-stats-bot", "--timezone", "Europe/Berlin"]);
+        assert!(format_match_report(&m, &ReportOptions::from_args(&args))
+            .contains("Match time: 2026\\-01\\-18 20:43 CET\n"));
+        assert!(Args::try_parse_from(["q3-stats-bot", "--timezone", "Mars/Olympus"]).is_err());
+
+        let garbled = xml.replace("1768765439", "yesterday");
+        let m = parse_content(garbled, &ParseOptions::default()).unwrap();
+        assert_eq!(m.timestamp, None);
+        assert!(!format_match_report(&m, &ReportOptions::default()).contains("Played:"));
+    }
+
+    #[test]
+    fn test_match_times() {
+        let xml = r#"<match map="q3dm6" type="TDM" startTime="1768765439" endTime="2026-01-18T19:53:59Z" duration="600">
+<player name="Solo"><stat name="Score" value="3"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let expected_start =
+            NaiveDateTime::parse_from_str("2026-01-18 19:43:59", "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .and_utc();
+        assert_eq!(result.start_time, Some(expected_start));
+        assert_eq!(
+            result.end_time,
+            Some(expected_start + chrono::Duration::seconds(600))
+        );
+        assert!(format_match_report(&result, &ReportOptions::default())
+            .contains("Match time: 2026\\-01\\-18 19:53 UTC"));
+
+        let untimed = Match {
+            file_time: Some(expected_start),
+            ..Default::default()
+        };
+        assert_eq!(untimed.played_at(), Some(expected_start));
+        assert_eq!(parse_timestamp("not a time"), None);
+    }
+
+    #[test]
+    fn test_fractional_numbers() {
+        let xml = r#"<match map="q3dm17" type="FFA" duration="452.7">
+<player name="Solo">
+<stat name="Score" value="12.5"/>
+<weapons><weapon name="RL" hits="9.0" shots="15.6" kills="3"/></weapons>
+</player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(humanize_duration(&result.duration), "7m 32s");
+        assert_eq!(format_duration(&result.duration), "7:32");
+
+        let rl = &result.teams[0].players[0].weapons[0];
+        assert_eq!((rl.hits, rl.shots, rl.kills), (9, 15, 3));
+        assert_eq!(parse_number(&result.teams[0].score), Some(12.5));
+
+        assert_eq!(humanize_duration("45"), "45s");
+        assert_eq!(humanize_duration("unknown"), "unknown");
+        assert_eq!(parse_count("-3"), 0);
+    }
+
+    #[test]
+    fn test_watch_player_filter() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let result = parse_content(xml, &ParseOptions::default()).unwrap();
+
+        let mut filter = MatchFilter {
+            watch_players: vec![normalize_name("Somebody Else")],
+            ..Default::default()
+        };
+        assert!(filter.skip_reason(&result).is_some());
+
+        filter.watch_players.push(normalize_name(" player3 "));
+        assert_eq!(filter.skip_reason(&result), None);
+
+        assert_eq!(MatchFilter::default().skip_reason(&result), None);
+    }
+
+    #[test]
+    fn test_ffa_unified_scoreboard() {
+        let xml = r#"<match map="q3dm17" type="FFA" isTeamGame="false" duration="600">
+<player name="Anarki"><stat name="Score" value="7"/></player>
+<player name="Bones"><stat name="Score" value="20"/></player>
+<player name="Crash"><stat name="Score" value="12"/></player>
+<player name="Doom"><stat name="Score" value="3"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert!(result.is_ffa());
+
+        let report = format_match_report(&result, &ReportOptions::default());
+        assert_eq!(report.matches("*Scoreboard*").count(), 1);
+        assert!(!report.contains("Team One"));
+        assert!(!report.contains("Team Two"));
+        assert!(report.contains(" 1. Bones   20\n 2. Crash   12\n 3. Anarki  7\n 4. Doom    3\n"));
+
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let tdm = parse_content(xml, &ParseOptions::default()).unwrap();
+        assert!(!tdm.is_ffa());
+        assert!(!format_match_report(&tdm, &ReportOptions::default()).contains("Scoreboard"));
+    }
+
+    #[test]
+    fn test_strip_color_codes() {
+        assert_eq!(strip_color_codes("^1KDZ^7:VaNeZzz"), "KDZ:VaNeZzz");
+        assert_eq!(strip_color_codes("^3A^4n^5a^6r^0ki^7"), "Anarki");
+        assert_eq!(strip_color_codes("foo^bar"), "foo^bar");
+        assert_eq!(strip_color_codes("trailing^"), "trailing^");
+        assert_eq!(strip_color_codes("^^1x"), "^x");
+
+        let xml = r#"<match map="^1q3dm6" type="1v1" duration="600">
+<player name="^1KDZ^7:VaNeZzz"><stat name="Score" value="5"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(result.map, "q3dm6");
+        assert_eq!(result.teams[0].players[0].name, "KDZ:VaNeZzz");
+    }
+
+    #[test]
+    fn test_ffa_photo_finish() {
+        let xml = r#"<match map="q3dm17" type="FFA" isTeamGame="false" duration="600">
+<player name="Anarki"><stat name="Score" value="19"/></player>
+<player name="Bones"><stat name="Score" value="20"/></player>
+<player name="Crash"><stat name="Score" value="4"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            photo_finish: Some(1.0),
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        assert!(report.contains("😤 Photo finish: Bones edged Anarki\n"));
+        assert!(!format_match_report(&result, &ReportOptions::default()).contains("Photo finish"));
+
+        let tight = ReportOptions {
+            photo_finish: Some(0.5),
+            ..Default::default()
+        };
+        assert!(!format_match_report(&result, &tight).contains("Photo finish"));
+
+        let lone = Team::default();
+        assert_eq!(photo_finish(&[&lone], 1.0), None);
+    }
+
+    #[test]
+    fn test_ffa_orders_by_place_without_score() {
+        let xml = r#"<match map="q3dm17" type="FFA" isTeamGame="false" duration="600">
+<player name="Anarki"><stat name="Place" value="2"/></player>
+<player name="Bones"><stat name="Place" value="3"/></player>
+<player name="Crash"><stat name="Place" value="1"/></player>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert!(result.is_ffa());
+        assert_eq!(result.teams[2].place, Some(1));
+
+        let report = format_match_report(&result, &ReportOptions::default());
+        assert!(report.contains(" 1. Crash   \n 2. Anarki  \n 3. Bones   \n"));
+
+        let by_rank = ParseOptions {
+            placement_stats: vec!["Rank".to_string()],
+            ..ParseOptions::default()
+        };
+        let result = parse_content(xml.to_string(), &by_rank).unwrap();
+        assert!(result.teams.iter().all(|t| t.place.is_none()));
+
+        let floats = xml.replace(r#"value="1""#, r#"value="1.0""#);
+        let result = parse_content(
+            floats.replace(r#"value="3""#, r#"value=" 3.0 ""#),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let places: Vec<_> = result.teams.iter().map(|t| t.place).collect();
+        assert_eq!(places, [Some(2), Some(3), Some(1)]);
+    }
+
+    #[test]
+    fn test_team_accuracy_median() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team score="10">
+<player name="A"><weapon name="RG" hits="1" shots="10" kills="1"/></player>
+<player name="B"><weapon name="RG" hits="4" shots="10" kills="2"/></player>
+<player name="C"><weapon name="RG" hits="10" shots="10" kills="7"/></player>
+<player name="Idle"><stat name="Score" value="0"/></player>
+</team>
+<team score="3"><player name="D"><stat name="Score" value="3"/></player></team>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let team = &m.teams[0];
+        assert_eq!(team.accuracy(TeamAccuracyStat::Mean), Some(50.0));
+        assert_eq!(team.accuracy(TeamAccuracyStat::Median), Some(40.0));
+        assert_eq!(m.teams[1].accuracy(TeamAccuracyStat::Median), None);
+
+        let args = Args::parse_from(["q3-stats-bot", "--team-accuracy-stat", "median"]);
+        let report = format_match_report(&m, &ReportOptions::from_args(&args));
+        assert!(report.contains("*Team One* 🏆: *10*\nTeam accuracy: 40% \\(median\\)\n"));
+        assert_eq!(report.matches("Team accuracy").count(), 1);
+
+        let mut even = m;
+        even.teams[0].players.remove(0);
+        assert_eq!(even.teams[0].accuracy(TeamAccuracyStat::Median), Some(70.0));
+    }
 
-        // Team Two (Score 0)
-        assert_eq!(result.teams[1].score, "0");
-        assert_eq!(result.teams[1].players.len(), 2);
-        assert_eq!(result.teams[1].players[0].name, "Player2");
-        assert_eq!(result.teams[1].players[1].name, "Player3");
+    #[test]
+    fn test_winning_team_marker() {
+        let report = |scores: (&str, &str), opts: &ReportOptions| {
+            let xml = format!(
+                r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team score="{}"><player name="A"><stat name="Score" value="1"/></player></team>
+<team score="{}"><player name="B"><stat name="Score" value="1"/></player></team>
+</match>"#,
+                scores.0, scores.1
+            );
+            render_report(&parse_content(xml, &ParseOptions::default()).unwrap(), opts).text
+        };
+        let markdown = ReportOptions::default();
+        let won = report(("2", "5"), &markdown);
+        assert!(won.contains("*Team One*: *2*\n"));
+        assert!(won.contains("*Team Two* 🏆: *5*\n"));
 
-        let report = format_match_report(&result);
-        assert!(report.contains("Duration: 10:01"));
+        let drawn = report(("3", "3"), &markdown);
+        assert!(drawn.contains("*Team One* \\(DRAW\\): *3*\n"));
+        assert!(drawn.contains("*Team Two* \\(DRAW\\): *3*\n"));
+
+        assert!(!report(("3", "n/a"), &markdown).contains(['🏆', '(']));
+
+        let plain = ReportOptions {
+            format: ReportFormat::Plain,
+            ..Default::default()
+        };
+        assert!(report(("7", "5"), &plain).contains("Team One (WINNER): 7\n"));
+    }
+
+    #[test]
+    fn test_drawn_match() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team score="3">
+<player name="A"><stat name="Score" value="9"/><stat name="Kills" value="9"/><stat name="Deaths" value="4"/></player>
+<player name="B"><stat name="Score" value="9"/><stat name="Kills" value="11"/><stat name="Deaths" value="6"/></player>
+</team>
+<team score="3">
+<player name="C"><stat name="Score" value="9"/><stat name="Kills" value="11"/><stat name="Deaths" value="5"/></player>
+<player name="D"><stat name="Score" value="2"/><stat name="Kills" value="2"/></player>
+</team>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(match_outcome(&m), Outcome::Draw);
+        assert_eq!(m.winning_team(), None);
+        // level on score and kills, so the fewer deaths win it
+        assert_eq!(m.mvp().map(|p| p.name.as_str()), Some("C"));
+
+        let args = Args::parse_from(["q3-stats-bot", "--mvp"]);
+        let report = format_match_report(&m, &ReportOptions::from_args(&args));
+        assert!(report.starts_with("⚔️ *Match concluded*\n🤝 Drawn match\n"));
+        assert!(report.contains("MVP: C\n"));
+        assert!(report.contains("*Team Two* \\(DRAW\\): *3*\n"));
+        assert!(format_match_summary(&m, &ReportOptions::default()).contains("*3* \\(draw\\)\n"));
+        assert!(!format_match_report(&m, &ReportOptions::default()).contains("MVP"));
+
+        let mut won = m;
+        won.teams[1].score = "4".to_string();
+        assert_eq!(match_outcome(&won), Outcome::Win(1));
+        let report = format_match_report(&won, &ReportOptions::default());
+        assert!(!report.contains("Drawn"));
+
+        won.teams[1].score = "n/a".to_string();
+        assert_eq!(match_outcome(&won), Outcome::Unknown);
+    }
+
+    #[test]
+    fn test_team_color_emoji() {
+        let xml = r#"<match map="q3ctf1" type="CTF" isTeamGame="true" duration="600">
+<team name="Reds" color="Red" score="3"><player name="A"/></team>
+<team name="Others" score="1"><player name="B"/></team>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(result.teams[0].color, "Red");
+
+        let opts = ReportOptions {
+            team_emojis: default_team_emojis(),
+            ..Default::default()
+        };
+        let report = format_match_report(&result, &opts);
+        assert!(report.contains("🔴 *Team One* 🏆: *3*\n"));
+        assert!(report.contains("\n*Team Two*: *1*\n"));
+    }
+
+    #[test]
+    fn test_same_match_throttle() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let first = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        // a live rewrite of the same match with different player order
+        let mut rewrite = parse_content(xml, &ParseOptions::default()).unwrap();
+        rewrite.teams.reverse();
+        assert_eq!(first.identity_key(), rewrite.identity_key());
+
+        let mut throttle = MatchThrottle::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(throttle.allow(&first.identity_key(), start));
+        assert!(!throttle.allow(&rewrite.identity_key(), start + Duration::from_secs(30)));
+        assert!(throttle.allow("q3dm17|other", start + Duration::from_secs(30)));
+        assert!(throttle.allow(&first.identity_key(), start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_event_debounce_per_path() {
+        let mut debounce = EventDebounce::new(Duration::from_millis(2000));
+        let start = Instant::now();
+        let first = Path::new("/stats/a.xml");
+        let second = Path::new("/stats/b.xml");
+        assert!(debounce.allow(first, start));
+        assert!(debounce.allow(second, start));
+        assert!(!debounce.allow(first, start + Duration::from_millis(50)));
+        assert!(!debounce.allow(second, start + Duration::from_millis(1999)));
+        assert!(debounce.allow(first, start + Duration::from_millis(2000)));
+
+        let mut off = EventDebounce::new(Duration::ZERO);
+        assert!(off.allow(first, start));
+        assert!(off.allow(first, start));
+    }
+
+    #[test]
+    fn test_startup_grace_holds_then_releases() {
+        let start = Instant::now();
+        let mut grace = StartupGrace::new(start + Duration::from_secs(30));
+        assert_eq!(grace.hold("first", start), None);
+        assert_eq!(grace.hold("second", start + Duration::from_secs(10)), None);
+        assert!(grace.release(start + Duration::from_secs(29)).is_empty());
+
+        let after = start + Duration::from_secs(30);
+        assert_eq!(grace.remaining(after), None);
+        assert_eq!(grace.release(after), vec!["first", "second"]);
+        assert!(grace.release(after).is_empty());
+        assert_eq!(grace.hold("late", after), Some("late"));
+    }
+
+    #[test]
+    fn test_only_on_change() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let first = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        let mut same = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        same.teams.reverse();
+        let mut changed = parse_content(xml, &ParseOptions::default()).unwrap();
+        changed.teams[0].score.push('1');
+
+        let mut last_sent = LastSent::default();
+        assert!(last_sent.changed(&first));
+        assert!(!last_sent.changed(&same));
+        assert!(last_sent.changed(&changed));
+        assert!(!last_sent.changed(&changed));
+        assert!(last_sent.changed(&first));
+    }
+
+    #[test]
+    fn test_report_number_footer() {
+        let store = StateStore::default();
+        let mut first = "*Match concluded*\n".to_string();
+        let mut second = "map=q3dm6\n".to_string();
+        store
+            .update(|s| append_report_number(&mut first, s.next_report_id(), true))
+            .unwrap();
+        store
+            .update(|s| append_report_number(&mut second, s.next_report_id(), false))
+            .unwrap();
+        assert_eq!(first, "*Match concluded*\n\\#1\n");
+        assert_eq!(second, "map=q3dm6\nreport=2\n");
+    }
+
+    #[test]
+    fn test_session_groups_split_on_gap() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut sessions = SessionGroups::new(Duration::from_secs(60));
+        assert_eq!(sessions.push("first", at(0)), None);
+        assert_eq!(sessions.push("second", at(30)), None);
+        assert_eq!(sessions.flush_due(at(90)), None);
+        assert_eq!(
+            sessions.push("third", at(120)),
+            Some(vec!["first", "second"])
+        );
+        assert_eq!(sessions.flush_due(at(150)), None);
+        assert_eq!(sessions.flush_due(at(181)), Some(vec!["third"]));
+        assert_eq!(sessions.flush_due(at(500)), None);
+
+        let report = |text: &str| Outgoing::new(text.to_string());
+        let combined = combine_session(vec![report("one\n"), report("two\n")], true, "");
+        assert_eq!(
+            combined.text,
+            "*Session: 2 matches*\n\n*Match 1/2*\none\n\n*Match 2/2*\ntwo\n"
+        );
+        assert_eq!(
+            combine_session(vec![report("solo\n")], true, "━━━━━"),
+            report("solo\n")
+        );
+    }
+
+    #[test]
+    fn test_session_match_divider() {
+        let report = |text: &str| Outgoing::new(text.to_string());
+        let combined = combine_session(
+            vec![report("```\none\n```\n"), report("two\n")],
+            true,
+            "━━━━━",
+        );
+        assert_eq!(combined.text.matches("━━━━━").count(), 1);
+        assert!(combined
+            .text
+            .contains("```\none\n```\n\n━━━━━\n\n*Match 2/2*\ntwo\n"));
+
+        // escaped for MarkdownV2, as is for plain text
+        let combined = combine_session(vec![report("one\n"), report("two\n")], true, "-- * --");
+        assert!(combined.text.contains("\n\\-\\- \\* \\-\\-\n"));
+        let combined = combine_session(vec![report("one\n"), report("two\n")], false, "-- * --");
+        assert!(combined.text.contains("\n-- * --\n"));
+
+        // never left inside an unclosed code block
+        let combined = combine_session(vec![report("```\ncut"), report("two\n")], true, "~~");
+        assert!(combined.text.contains("```\ncut\n```\n\n\\~\\~\n"));
+    }
+
+    #[test]
+    fn test_end_reason_from_limits() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="540" fraglimit="50" timelimit="10">
+<team name="Red" score="50"><player name="A"><stat name="Kills" value="50"/></player></team>
+<team name="Blue" score="31"><player name="B"><stat name="Kills" value="31"/></player></team>
+</match>"#;
+        let mut m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!((m.frag_limit, m.time_limit), (Some(50), Some(10)));
+        assert_eq!(m.end_reason(), Some("frag limit reached"));
+        assert!(format_match_report(&m, &ReportOptions::default())
+            .contains("Ended: frag limit reached\n"));
+
+        // both apply: the reached frag limit is what ended it
+        m.duration = "600".to_string();
+        assert_eq!(m.end_reason(), Some("frag limit reached"));
+
+        m.teams[0].score = "42".to_string();
+        assert_eq!(m.end_reason(), Some("time limit"));
+        m.time_limit = None;
+        assert_eq!(m.end_reason(), None);
+    }
+
+    #[test]
+    fn test_rematch_detection() {
+        let xml = std::fs::read_to_string("test.xml").expect("Unable to read test.xml");
+        let at = |m: &mut Match, minute: u32| {
+            m.end_time =
+                Some(DateTime::from_timestamp(1_700_000_000 + i64::from(minute) * 60, 0).unwrap());
+        };
+        let mut first = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        at(&mut first, 0);
+        let mut restart = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        at(&mut restart, 2);
+        let mut much_later = parse_content(xml.clone(), &ParseOptions::default()).unwrap();
+        at(&mut much_later, 60);
+        let mut other_map = parse_content(xml, &ParseOptions::default()).unwrap();
+        other_map.map = "q3dm17".to_string();
+        at(&mut other_map, 61);
+
+        let mut detector = RematchDetector::new(chrono::Duration::minutes(5));
+        assert!(!detector.is_rematch(&first));
+        assert!(detector.is_rematch(&restart));
+        assert!(!detector.is_rematch(&much_later));
+        assert!(!detector.is_rematch(&other_map));
+    }
+
+    #[test]
+    fn test_parse_failures_are_parse_errors() {
+        let opts = ParseOptions::default();
+        assert!(matches!(
+            parse_content("<match><team></match>".to_string(), &opts),
+            Err(BotError::Parse(_))
+        ));
+        assert!(matches!(
+            parse_content("no xml here".to_string(), &opts),
+            Err(BotError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_watching_missing_folder_is_a_watch_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = create_watcher(|_| {}, None).unwrap();
+        let err = watch_folder(watcher.as_mut(), &dir.path().join("missing")).unwrap_err();
+        assert!(matches!(err, BotError::Watch(_)));
+        assert!(watch_folder(watcher.as_mut(), dir.path()).is_ok());
+    }
+
+    #[test]
+    fn test_min_winning_score() {
+        let xml = r#"<match map="q3dm17" type="FFA" duration="300">
+<player name="A"><stat name="Score" value="1"/></player>
+<player name="B"><stat name="Score" value="0"/></player>
+</match>"#;
+        let mut m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(m.top_score(), Some(1.0));
+
+        let filter = MatchFilter {
+            min_winning_score: Some(3.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.skip_reason(&m),
+            Some("top score 1 is below 3".to_string())
+        );
+
+        m.teams[1].score = "3".to_string();
+        assert_eq!(filter.skip_reason(&m), None);
+        for team in &mut m.teams {
+            team.score = "n/a".to_string();
+        }
+        assert_eq!(filter.skip_reason(&m), None);
+    }
+
+    #[test]
+    fn test_skip_empty_matches() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team name="Red" score="0"/>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(MatchFilter::default().skip_reason(&m), None);
+
+        let args = Args::parse_from(["q3-stats-bot", "--skip-empty-matches"]);
+        let filter = MatchFilter::from_args(&args);
+        assert_eq!(
+            filter.skip_reason(&m),
+            Some("no players in the match".to_string())
+        );
+
+        let headless = r#"<match map="q3dm6" type="FFA" duration="0"></match>"#;
+        let m = parse_content(headless.to_string(), &ParseOptions::default()).unwrap();
+        assert!(m.teams.is_empty());
+        assert!(filter.skip_reason(&m).is_some());
+
+        let played =
+            r#"<match map="q3dm6" type="FFA" duration="60"><player name="A"></player></match>"#;
+        let m = parse_content(played.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(filter.skip_reason(&m), None);
+    }
+
+    #[test]
+    fn test_watch_team_wins_only() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team name="Red" score="3"><player name="A"/></team>
+<team name="Blue" score="7"><player name="B"/></team>
+</match>"#;
+        let result = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        assert_eq!(result.winning_team(), Some(1));
+
+        let mut filter = MatchFilter {
+            watch_team: Some("red".to_string()),
+            wins_only: true,
+            ..Default::default()
+        };
+        assert!(filter.skip_reason(&result).is_some());
+
+        filter.watch_team = Some("Blue".to_string());
+        assert_eq!(filter.skip_reason(&result), None);
+
+        filter.watch_team = Some("Green".to_string());
+        assert!(filter.skip_reason(&result).is_some());
+
+        let opts = ReportOptions {
+            watch_team: Some("red".to_string()),
+            ..Default::default()
+        };
+        assert!(format_match_report(&result, &opts).contains("*LOSS* for Red"));
+
+        let mut unscored = result;
+        unscored.teams[1].score = "n/a".to_string();
+        assert_eq!(match_outcome(&unscored), Outcome::Unknown);
+        let report = format_match_report(&unscored, &opts);
+        assert!(!report.contains(" for Red"));
+        assert!(!report.contains("DRAW"));
+        filter.watch_team = Some("Red".to_string());
+        assert!(filter.skip_reason(&unscored).is_some());
+    }
+
+    #[test]
+    fn test_stat_formatter_default() {
+        let fmt = StatFormatter::default();
+        assert_eq!(fmt.ratio(2.5), "2.50");
+        assert_eq!(fmt.ratio(1.0 / 3.0), "0.33");
+        assert_eq!(fmt.percent(44), "44%");
+        assert_eq!(fmt.integer(1234567), "1234567");
+    }
+
+    #[test]
+    fn test_stat_formatter_european() {
+        let fmt = StatFormatter {
+            decimal_separator: ',',
+            thousands_separator: Some('.'),
+            ratio_precision: 1,
+        };
+        assert_eq!(fmt.ratio(2.5), "2,5");
+        assert_eq!(fmt.ratio(0.26), "0,3");
+        assert_eq!(fmt.percent(100), "100%");
+        assert_eq!(fmt.integer(1234567), "1.234.567");
+        assert_eq!(fmt.integer(999), "999");
+        assert_eq!(fmt.integer(0), "0");
+    }
+
+    #[test]
+    fn test_overflow_rescans_unprocessed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("seen.xml");
+        let missed = dir.path().join("nested").join("missed.xml");
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(&seen, "<match/>").unwrap();
+        std::fs::write(&missed, "<match/>").unwrap();
+
+        let processed = HashMap::from([(seen.clone(), modified_time(&seen).unwrap())]);
+        let overflow = notify::Event::new(EventKind::Other).set_flag(notify::event::Flag::Rescan);
+        assert_eq!(
+            files_for_event(&overflow, dir.path(), &processed),
+            vec![missed.clone()]
+        );
+
+        let create = notify::Event::new(EventKind::Create(notify::event::CreateKind::File))
+            .add_path(seen.clone());
+        assert_eq!(files_for_event(&create, dir.path(), &processed), vec![seen]);
+    }
+
+    #[test]
+    fn test_writes_and_renames_into_place_are_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let fpath = dir.path().join("match.xml");
+        let tmp = dir.path().join("match.xml.tmp");
+        std::fs::write(&fpath, "<match/>").unwrap();
+        let written = notify::Event::new(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Content,
+        )))
+        .add_path(fpath.clone());
+        let renamed = notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(tmp.clone())
+            .add_path(fpath.clone());
+        let renamed_away =
+            notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+                .add_path(fpath.clone());
+
+        let none = HashMap::new();
+        assert_eq!(
+            files_for_event(&written, dir.path(), &none),
+            vec![fpath.clone()]
+        );
+        assert_eq!(
+            files_for_event(&renamed, dir.path(), &none),
+            vec![fpath.clone()]
+        );
+        assert!(files_for_event(&renamed_away, dir.path(), &none).is_empty());
+
+        let processed = HashMap::from([(fpath.clone(), modified_time(&fpath).unwrap())]);
+        assert!(files_for_event(&written, dir.path(), &processed).is_empty());
+        let stale = HashMap::from([(fpath.clone(), SystemTime::UNIX_EPOCH)]);
+        assert_eq!(files_for_event(&written, dir.path(), &stale), vec![fpath]);
+    }
+
+    #[test]
+    fn test_startup_scan_skips_old_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old.xml");
+        let fresh = dir.path().join("fresh.xml");
+        std::fs::write(&old, "<match/>").unwrap();
+        std::fs::write(&fresh, "<match/>").unwrap();
+
+        let now = SystemTime::now();
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(48 * 3600))
+            .unwrap();
+
+        std::fs::write(dir.path().join("notes.txt"), "not a match").unwrap();
+
+        let none = HashMap::new();
+        let xml = ["xml".to_string()];
+        let day = Some(Duration::from_secs(24 * 3600));
+        assert_eq!(
+            startup_files(dir.path(), &none, &xml, day, now).unwrap(),
+            vec![fresh.clone()]
+        );
+        assert_eq!(
+            startup_files(dir.path(), &none, &xml, None, now).unwrap(),
+            vec![fresh.clone(), old.clone()]
+        );
+
+        let processed = HashMap::from([(fresh.clone(), modified_time(&fresh).unwrap())]);
+        assert_eq!(
+            startup_files(dir.path(), &processed, &xml, None, now).unwrap(),
+            vec![old.clone()]
+        );
+        // rewritten since it was processed
+        let processed = HashMap::from([(fresh.clone(), SystemTime::UNIX_EPOCH)]);
+        assert_eq!(
+            startup_files(dir.path(), &processed, &xml, None, now).unwrap(),
+            vec![fresh, old]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_scan_and_watcher_report_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let fpath = dir.path().join("match.xml");
+        std::fs::copy("test.xml", &fpath).unwrap();
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        let mut processed = ProcessedFiles::load(&reporter.store);
+
+        // the startup scan, then the event that raced it
+        report_once(&mut reporter, &mut processed, fpath.clone()).await;
+        report_once(&mut reporter, &mut processed, fpath.clone()).await;
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        let recorded = reporter
+            .store
+            .read(|s| s.processed_files.get(&fpath).copied());
+        assert_eq!(recorded, modified_time(&fpath));
+
+        // a rewritten file is new again
+        std::fs::File::options()
+            .write(true)
+            .open(&fpath)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        report_once(&mut reporter, &mut processed, fpath.clone()).await;
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // one that doesn't parse yet is left for the next write
+        let broken = dir.path().join("broken.xml");
+        std::fs::write(&broken, "<match map=").unwrap();
+        report_once(&mut reporter, &mut processed, broken.clone()).await;
+        assert!(!processed.files.contains_key(&broken));
+        reporter
+            .store
+            .read(|s| assert!(!s.processed_files.contains_key(&broken)));
+        std::fs::copy("test.xml", &broken).unwrap();
+        report_once(&mut reporter, &mut processed, broken.clone()).await;
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+
+    /// What `monitor_folder` does with one watcher event.
+    async fn handle_event(
+        reporter: &mut Reporter,
+        processed: &mut ProcessedFiles,
+        root: &Path,
+        event: notify::Event,
+    ) {
+        for fpath in files_for_event(&event, root, &processed.files) {
+            report_once(reporter, processed, fpath).await;
+        }
+    }
+
+    /// Writes `content` to `path`, dated `minutes` from now so each version
+    /// gets its own mtime.
+    fn write_version(path: &Path, content: &str, minutes: u64) {
+        std::fs::write(path, content).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60 * minutes))
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rewritten_file_is_reported_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let fpath = dir.path().join("match.xml");
+        let tmp = dir.path().join("match.xml.tmp");
+        let xml = std::fs::read_to_string("test.xml").unwrap();
+        let later = xml.replace(r#"score="0""#, r#"score="3""#);
+        let args = Args::parse_from(["q3-stats-bot", "--only-on-change"]);
+        let (mut reporter, sent) = recording_reporter(ReportOptions::from_args(&args));
+        reporter.last_sent = args.only_on_change.then(LastSent::default);
+        let mut processed = ProcessedFiles::load(&reporter.store);
+        let written = || {
+            notify::Event::new(EventKind::Modify(ModifyKind::Data(
+                notify::event::DataChange::Content,
+            )))
+            .add_path(fpath.clone())
+        };
+        let renamed = || {
+            notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path(tmp.clone())
+                .add_path(fpath.clone())
+        };
+
+        write_version(&fpath, &xml, 1);
+        let created = notify::Event::new(EventKind::Create(notify::event::CreateKind::File))
+            .add_path(fpath.clone());
+        handle_event(&mut reporter, &mut processed, dir.path(), created).await;
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        // the writes that made up that version
+        assert!(files_for_event(&written(), dir.path(), &processed.files).is_empty());
+
+        write_version(&fpath, &later, 2);
+        handle_event(&mut reporter, &mut processed, dir.path(), written()).await;
+        assert_eq!(sent.lock().unwrap().len(), 2);
+        assert!(sent.lock().unwrap()[1].1.contains("*3*"));
+
+        // a temp file renamed into place, first with nothing new in it
+        write_version(&tmp, &later, 3);
+        std::fs::rename(&tmp, &fpath).unwrap();
+        handle_event(&mut reporter, &mut processed, dir.path(), renamed()).await;
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        write_version(&tmp, &xml, 4);
+        std::fs::rename(&tmp, &fpath).unwrap();
+        handle_event(&mut reporter, &mut processed, dir.path(), renamed()).await;
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_held_report_is_recorded_once_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let fpath = dir.path().join("match.xml");
+        std::fs::copy("test.xml", &fpath).unwrap();
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        reporter.grace = Some(StartupGrace::new(
+            Instant::now() + Duration::from_secs(3600),
+        ));
+        let mut processed = ProcessedFiles::load(&reporter.store);
+
+        report_once(&mut reporter, &mut processed, fpath.clone()).await;
+        processed.save_released(&reporter);
+        assert!(sent.lock().unwrap().is_empty());
+        reporter
+            .store
+            .read(|s| assert!(s.processed_files.is_empty()));
+        // not reported twice while it waits
+        report_once(&mut reporter, &mut processed, fpath.clone()).await;
+
+        reporter.grace.as_mut().unwrap().until = Instant::now();
+        reporter.tick().await;
+        processed.save_released(&reporter);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        reporter
+            .store
+            .read(|s| assert!(s.processed_files.contains_key(&fpath)));
+    }
+
+    #[test]
+    fn test_match_file_extensions() {
+        let args = Args::parse_from(["q3-stats-bot"]);
+        let xml = &args.extensions;
+        assert!(is_match_file(Path::new("stats/match.xml"), xml));
+        assert!(is_match_file(Path::new("match.XML"), xml));
+        assert!(is_match_file(Path::new("match.xml.gz"), xml));
+        assert!(!is_match_file(Path::new("demo.dm_68"), xml));
+        assert!(!is_match_file(Path::new("match.xml.tmp"), xml));
+        assert!(!is_match_file(Path::new("demo.dm_68.gz"), xml));
+        // no extension at all, even with the list allowing an empty one
+        assert!(!is_match_file(Path::new("match"), xml));
+        assert!(!is_match_file(Path::new("match.gz"), xml));
+        assert!(!is_match_file(Path::new(".xml"), xml));
+        assert!(!is_match_file(Path::new("match"), &[String::new()]));
+
+        let args = Args::parse_from(["q3-stats-bot", "--extensions", "xml,.log"]);
+        assert_eq!(args.extensions, ["xml", ".log"]);
+        assert!(is_match_file(Path::new("match.log"), &args.extensions));
+        assert!(is_match_file(Path::new("match.xml"), &args.extensions));
+    }
+
+    #[test]
+    fn test_events_for_watched_folder_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        // a root that was just removed no longer looks like a directory
+        let root = dir.path().join("gone");
+        let processed = HashMap::new();
+
+        let metadata = notify::Event::new(EventKind::Modify(notify::event::ModifyKind::Metadata(
+            notify::event::MetadataKind::Any,
+        )))
+        .add_path(root.clone());
+        assert!(files_for_event(&metadata, &root, &processed).is_empty());
+
+        let create = notify::Event::new(EventKind::Create(notify::event::CreateKind::Any))
+            .add_path(root.clone());
+        assert!(files_for_event(&create, &root, &processed).is_empty());
+
+        let inside = root.join("match.xml");
+        let create = notify::Event::new(EventKind::Create(notify::event::CreateKind::File))
+            .add_path(inside.clone());
+        assert_eq!(files_for_event(&create, &root, &processed), vec![inside]);
+    }
+
+    #[test]
+    fn test_permission_denied_at_setup_is_fatal() {
+        let path = Path::new("/srv/q3/stats");
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = watch_setup_error(path, denied.into());
+        assert!(matches!(err, BotError::Config(_)));
+        let err = err.to_string();
+        assert!(err.starts_with(r#"Permission denied reading "/srv/q3/stats""#));
+        assert!(err.contains("chmod -R o+rX /srv/q3/stats"));
+
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let err = watch_setup_error(path, missing.into());
+        assert!(matches!(err, BotError::Watch(_)));
+        assert!(!err.to_string().contains("chmod"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_folder_tags() {
+        assert_eq!(slugify("Duel Server"), "duel-server");
+        assert_eq!(slugify("  FFA__server (EU) "), "ffa-server-eu");
+        assert_eq!(
+            folder_tag(Path::new("/srv/q3/xmlstats/Duel Server/match.xml")).as_deref(),
+            Some("duel-server")
+        );
+        assert_eq!(folder_tag(Path::new("/srv/!!!/match.xml")), None);
+        assert_eq!(folder_tag(Path::new("match.xml")), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("Duel Server");
+        std::fs::create_dir(&folder).unwrap();
+        let path = folder.join("match.xml");
+        std::fs::copy("test.xml", &path).unwrap();
+        for (format, tag) in [
+            ("markdown", "\\#duel\\-server\n"),
+            ("plain", "#duel-server\n"),
+        ] {
+            let args = Args::parse_from(["q3-stats-bot", "--format", format]);
+            let (mut reporter, sent) = recording_reporter(ReportOptions::from_args(&args));
+            reporter.folder_tags = true;
+            reporter.process_file(&path).await;
+            reporter.folder_tags = false;
+            reporter.process_file(&path).await;
+            let sent = sent.lock().unwrap();
+            assert!(sent[0].1.contains(tag), "{}", sent[0].1);
+            assert!(!sent[1].1.contains("duel"));
+        }
+    }
+
+    #[test]
+    fn test_dry_run_leaves_storage_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let db_path = dir.path().join("matches.db");
+        StateStore::open(Some(state_path.clone()))
+            .unwrap()
+            .update(|s| s.subscribe(ChatId(-100)))
+            .unwrap();
+        let before = std::fs::read(&state_path).unwrap();
+
+        let state_arg = state_path.display().to_string();
+        let db_arg = db_path.display().to_string();
+        let args = Args::parse_from([
+            "q3-stats-bot",
+            "--dry-run",
+            "--state-file",
+            &state_arg,
+            "--db",
+            &db_arg,
+        ]);
+        let (store, db) = open_storage(&args).unwrap();
+        assert!(db.is_none());
+        store
+            .update(|s| {
+                s.mark_processed(dir.path().join("match.xml"), SystemTime::now());
+                s.next_report_id()
+            })
+            .unwrap();
+        assert_eq!(std::fs::read(&state_path).unwrap(), before);
+        assert!(!db_path.exists());
+
+        let args = Args::parse_from(["q3-stats-bot", "--state-file", &state_arg, "--db", &db_arg]);
+        let (store, db) = open_storage(&args).unwrap();
+        assert!(db.is_some());
+        store.read(|s| assert!(s.subscribed_chats.contains(&-100)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_stops_monitor() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("stats");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::copy("test.xml", folder.join("match.xml")).unwrap();
+        let db_path = dir.path().join("matches.db");
+
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        reporter.db = Some(MatchDb::open(&db_path).unwrap());
+        // asked to stop straight away, after the startup scan has run
+        monitor_folder(
+            reporter,
+            folder.display().to_string(),
+            None,
+            Duration::ZERO,
+            vec!["xml".to_string()],
+            Some(StartupScan { max_age: None }),
+            async {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        // the database was closed, so it opens again with the match in it
+        let mut db = MatchDb::open(&db_path).unwrap();
+        let m = parse_content(
+            std::fs::read_to_string("test.xml").unwrap(),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(db.insert_match(&m).unwrap().id, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_sends_held_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy("test.xml", dir.path().join("a.xml")).unwrap();
+        std::fs::copy("test.xml", dir.path().join("b.xml")).unwrap();
+        let state_path = dir.path().join("state.json");
+
+        for (session, grace) in [(true, false), (false, true), (true, true)] {
+            let _ = std::fs::remove_file(&state_path);
+            let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+            reporter.store = Arc::new(StateStore::open(Some(state_path.clone())).unwrap());
+            let hour = Duration::from_secs(3600);
+            reporter.sessions = session.then(|| SessionGroups::new(hour));
+            reporter.grace = grace.then(|| StartupGrace::new(Instant::now() + hour));
+            monitor_folder(
+                reporter,
+                dir.path().display().to_string(),
+                None,
+                Duration::ZERO,
+                vec!["xml".to_string()],
+                Some(StartupScan { max_age: None }),
+                async {},
+            )
+            .await
+            .unwrap();
+
+            let sent = sent.lock().unwrap();
+            let expected = if session { 1 } else { 2 };
+            assert_eq!(sent.len(), expected, "session {session}, grace {grace}");
+            let state = State::load(&state_path).unwrap();
+            assert_eq!(state.processed_files.len(), 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_spacing() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 1..=3 {
+            let xml = format!(
+                r#"<match map="q3dm{}" type="FFA" duration="60"><player name="A"></player></match>"#,
+                i
+            );
+            std::fs::write(dir.path().join(format!("{}.xml", i)), xml).unwrap();
+        }
+        std::fs::write(dir.path().join("notes.txt"), "not a match").unwrap();
+
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        let count = || sent.lock().unwrap().len();
+        let checks = async {
+            // each file is sent once it settles, three polls after its turn
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert_eq!(count(), 1);
+            tokio::time::sleep(Duration::from_millis(9500)).await;
+            assert_eq!(count(), 1);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(count(), 2);
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            assert_eq!(count(), 3);
+        };
+        let extensions = ["xml".to_string()];
+        let (replayed, ()) = tokio::join!(
+            replay(
+                &mut reporter,
+                dir.path(),
+                Duration::from_secs(10),
+                &extensions
+            ),
+            checks
+        );
+        assert_eq!(replayed.unwrap(), 3);
+
+        let sent = sent.lock().unwrap();
+        for (i, (_, text)) in sent.iter().enumerate() {
+            assert!(text.contains(&format!("Map: q3dm{} ", i + 1)), "{}", text);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_without_interval_sends_held_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 1..=2 {
+            std::fs::copy("test.xml", dir.path().join(format!("{}.xml", i))).unwrap();
+        }
+        let args = Args::parse_from([
+            "q3-stats-bot",
+            "--replay",
+            "stats",
+            "--replay-interval-seconds",
+            "0",
+        ]);
+        let hour = Duration::from_secs(3600);
+        let extensions = ["xml".to_string()];
+        for grace in [false, true] {
+            let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+            reporter.sessions = Some(SessionGroups::new(hour));
+            reporter.grace = grace.then(|| StartupGrace::new(Instant::now() + hour));
+            let interval = Duration::from_secs(args.replay_interval_seconds);
+            let replayed = replay(&mut reporter, dir.path(), interval, &extensions).await;
+            assert_eq!(replayed.unwrap(), 2);
+            // both in one session message
+            assert_eq!(sent.lock().unwrap().len(), 1, "grace {grace}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_after_interval() {
+        assert!(Args::try_parse_from(["q3-stats-bot", "--heartbeat-seconds", "0"]).is_err());
+        let args = Args::parse_from(["q3-stats-bot", "--heartbeat-seconds", "60"]);
+        assert_eq!(args.heartbeat_seconds, Some(60));
+
+        let metrics = Arc::new(Metrics::default());
+        metrics.file_processed();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        tokio::spawn(heartbeat(
+            metrics,
+            PathBuf::from("/srv/q3/stats"),
+            Duration::from_secs(60),
+            move |line| sink.lock().unwrap().push(line),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(lines.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["alive, 1 files processed, watching /srv/q3/stats"]
+        );
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(lines.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_settle_skips_removed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let transient = dir.path().join("match.xml.tmp");
+        let kept = dir.path().join("match.xml");
+        std::fs::write(&transient, "<match/>").unwrap();
+        std::fs::write(&kept, "<match/>").unwrap();
+
+        let remove = transient.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            std::fs::remove_file(remove).unwrap();
+        });
+
+        assert!(!settle_file(&transient, TEST_SETTLE).await);
+        assert!(settle_file(&kept, TEST_SETTLE).await);
+    }
+
+    const TEST_SETTLE: Settle = Settle {
+        poll: Duration::from_millis(250),
+        max_wait: Duration::from_secs(5),
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_settle_waits_for_stable_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("match.xml");
+        std::fs::write(&path, "<match>").unwrap();
+
+        // still growing for the first second, then done
+        let grow = path.clone();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&grow)
+                    .unwrap();
+                file.write_all(b"<player/>").unwrap();
+            }
+        });
+        let start = tokio::time::Instant::now();
+        assert!(settle_file(&path, TEST_SETTLE).await);
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // a file that never stops growing is given up on
+        let grow = path.clone();
+        let writer = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&grow)
+                    .unwrap();
+                file.write_all(b"<player/>").unwrap();
+            }
+        });
+        let start = tokio::time::Instant::now();
+        assert!(!settle_file(&path, TEST_SETTLE).await);
+        assert!(start.elapsed() >= TEST_SETTLE.max_wait);
+        writer.abort();
+    }
+
+    /// A reporter posting to chat 1 that records its sends instead of
+    /// calling Telegram.
+    fn recording_reporter(opts: ReportOptions) -> (Reporter, SentLog) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let reporter = Reporter {
+            sink: Sink::Recording(sent.clone()),
+            chat_ids: vec![ChatId(1)],
+            parse_opts: ParseOptions::default(),
+            report_opts: Arc::new(Mutex::new(opts)),
+            filter: MatchFilter::default(),
+            history_dir: None,
+            db: None,
+            status_file: None,
+            folder_tags: false,
+            store: Arc::new(StateStore::default()),
+            metrics: Arc::new(Metrics::default()),
+            throttle: None,
+            last_sent: None,
+            rematches: None,
+            sessions: None,
+            thread_id: None,
+            grace: None,
+            settle: TEST_SETTLE,
+            unreadable: HashSet::new(),
+        };
+        (reporter, sent)
+    }
+
+    #[tokio::test]
+    async fn test_message_per_team() {
+        let xml = r#"<match map="q3dm6" type="TDM" isTeamGame="true" duration="600">
+<team score="2"><player name="Alpha"><stat name="Score" value="1"/></player></team>
+<team score="5"><player name="Bravo"><stat name="Score" value="4"/></player></team>
+</match>"#;
+        let m = parse_content(xml.to_string(), &ParseOptions::default()).unwrap();
+        let opts = ReportOptions {
+            message_per_team: true,
+            ..Default::default()
+        };
+        let (mut reporter, sent) = recording_reporter(opts);
+        reporter
+            .report_match(Path::new("match.xml"), m, false)
+            .await;
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|(chat, _)| *chat == ChatId(1)));
+        assert!(sent[0].1.starts_with("*Match concluded*\n"));
+        assert!(!sent[0].1.contains("Alpha"));
+        assert!(sent[1].1.starts_with("*Team One*: *2*\n"));
+        assert!(sent[1].1.contains("Alpha") && !sent[1].1.contains("Bravo"));
+        assert!(sent[2].1.starts_with("*Team Two* 🏆: *5*\n"));
+        assert!(sent[2].1.ends_with("\\#1\n"));
+        assert_eq!(reporter.metrics.summary().lines().nth(2), Some("Sends: 3"));
+    }
+
+    #[test]
+    fn test_several_chat_ids() {
+        let args = Args::parse_from(["q3-stats-bot", "--chat-id", "-100227937281"]);
+        assert_eq!(args.chat_ids, ["-100227937281"]);
+
+        let args = Args::parse_from(["q3-stats-bot", "-c", "-100,-200", "--chat-id", "42"]);
+        assert_eq!(args.chat_ids, ["-100", "-200", "42"]);
+    }
+
+    #[test]
+    fn test_migration_error_moves_the_chat() {
+        let store = StateStore::default();
+        store.update(|s| s.subscribe(ChatId(-100))).unwrap();
+
+        let migrated = RequestError::MigrateToChatId(ChatId(-1001));
+        assert_eq!(
+            migrate_chat(&store, ChatId(-100), &migrated),
+            Some(ChatId(-1001))
+        );
+        store.read(|s| {
+            assert_eq!(s.resolve_chat(ChatId(-100)), ChatId(-1001));
+            assert!(s.subscribed_chats.contains(&-1001));
+            assert!(!s.subscribed_chats.contains(&-100));
+        });
+
+        let other = RequestError::RetryAfter(teloxide::types::Seconds::from_seconds(5));
+        assert_eq!(migrate_chat(&store, ChatId(42), &other), None);
+        store.read(|s| assert_eq!(s.resolve_chat(ChatId(42)), ChatId(42)));
+    }
+
+    #[test]
+    fn test_split_long_report() {
+        let mut text =
+            String::from("*Match concluded*\nMap: q3ctf1 \\| Type: CTF\n*Scoreboard*\n```\n");
+        for i in 0..40 {
+            text.push_str(&format!("{:>2}. Player{:<20} {}\n", i + 1, i, i * 3));
+        }
+        text.push_str("```\n");
+        text.push_str(&format!("*Big*: {}\n", r"\_".repeat(200)));
+
+        let chunks = split_message(&text, 300);
+        assert!(chunks.len() > 2);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 300, "{chunk}");
+            assert_eq!(chunk.matches("```\n").count() % 2, 0, "{chunk}");
+            let last = chunk.lines().last().unwrap();
+            assert!(!last.ends_with('\\') || last.ends_with(r"\\"), "{chunk}");
+        }
+        assert!(chunks[0].starts_with("*Match concluded*\nMap: "));
+        assert!(chunks[1..].iter().all(|c| !c.contains("Map: ")));
+        assert_eq!(
+            chunks.concat().replace("```\n", ""),
+            text.replace("```\n", "")
+        );
+
+        assert_eq!(split_message("short\n", MESSAGE_LIMIT), ["short\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_status_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.txt");
+        assert_eq!(read_status(&path).await, None);
+
+        std::fs::write(&path, "Players: 5/16\nNext map: q3dm17\n").unwrap();
+        let status = read_status(&path).await.unwrap();
+        let mut text = "*Match concluded*\n".to_string();
+        prepend_status(&mut text, &status, true);
+        assert_eq!(
+            text,
+            "📡 Players: 5/16 · Next map: q3dm17\n*Match concluded*\n"
+        );
+
+        let mut text = "map=q3dm6\n".to_string();
+        prepend_status(&mut text, &status, false);
+        assert_eq!(text, "status=Players: 5/16 · Next map: q3dm17\nmap=q3dm6\n");
+    }
+
+    #[tokio::test]
+    async fn test_gzipped_match_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let gz = dir.path().join("match.xml.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"<match map=\"q3dm6\"/>").unwrap();
+        std::fs::write(&gz, encoder.finish().unwrap()).unwrap();
+        let data = read_match_file(&gz, Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(data, "<match map=\"q3dm6\"/>");
+
+        let bogus = dir.path().join("bogus.xml.gz");
+        std::fs::write(&bogus, "<match/>").unwrap();
+        assert!(read_match_file(&bogus, Duration::ZERO).await.is_err());
+    }
+
+    /// Keeps every log line, so a test can check how something was logged.
+    struct CaptureLog;
+
+    static CAPTURED: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CaptureLog {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = record.args().to_string();
+            CAPTURED.lock().unwrap().push((record.level(), line));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Starts capturing log lines. Tests run side by side, so look for
+    /// something only yours logs, like a temp file's path.
+    fn capture_logs() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLog).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    fn logs_mentioning(needle: &str) -> Vec<(log::Level, String)> {
+        CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, line)| line.contains(needle))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_byte_file_is_retried_then_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.xml");
+        std::fs::write(&empty, "").unwrap();
+        assert_eq!(
+            read_match_file(&empty, Duration::from_millis(10))
+                .await
+                .unwrap(),
+            None
+        );
+
+        let late = dir.path().join("late.xml");
+        std::fs::write(&late, "").unwrap();
+        let writer = late.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            std::fs::write(writer, "<match/>").unwrap();
+        });
+        assert_eq!(
+            read_match_file(&late, Duration::from_millis(200))
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("<match/>")
+        );
+
+        // skipped quietly, and reported once it has something in it
+        capture_logs();
+        let (mut reporter, sent) = recording_reporter(ReportOptions::default());
+        let mut processed = ProcessedFiles::load(&reporter.store);
+        report_once(&mut reporter, &mut processed, empty.clone()).await;
+        let logs = logs_mentioning(&format!("{:?}", empty));
+        assert!(logs.contains(&(
+            log::Level::Debug,
+            format!("{:?} stayed empty, skipping", empty)
+        )));
+        assert!(
+            logs.iter().all(|(level, _)| *level > log::Level::Warn),
+            "{logs:?}"
+        );
+        assert!(sent.lock().unwrap().is_empty());
+
+        write_version(&empty, &std::fs::read_to_string("test.xml").unwrap(), 1);
+        report_once(&mut reporter, &mut processed, empty).await;
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_poll_watcher_detects_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = create_watcher(tx, Some(50)).unwrap();
+        watcher
+            .watch(dir.path(), notify::RecursiveMode::Recursive)
+            .unwrap();
+
+        let fpath = dir.path().join("match.xml");
+        std::fs::write(&fpath, "<match/>").unwrap();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let event = rx
+                .recv_timeout(remaining)
+                .expect("no create event from poll watcher")
+                .unwrap();
+            if matches!(event.kind, EventKind::Create(_)) && event.paths.contains(&fpath) {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_topic_only_for_groups() {
+        let thread = Some(ThreadId(MessageId(42)));
+        assert_eq!(topic_for(ChatId(-1001234567890), thread), thread);
+        assert_eq!(topic_for(ChatId(-4567), thread), thread);
+        assert_eq!(topic_for(ChatId(4567), thread), None);
+        assert_eq!(topic_for(ChatId(-100), None), None);
+    }
+
+    #[test]
+    fn test_message_thread_id_in_request() {
+        use teloxide::requests::HasPayload;
+
+        let bot = Bot::new("0:token");
+        let thread = ThreadId(MessageId(42));
+        let request = build_message(&bot, ChatId(-100), "report".to_string(), Some(thread));
+        let payload = request.payload_ref();
+        assert_eq!(payload.message_thread_id, Some(thread));
+        assert_eq!(payload.text, "report");
+
+        let request = build_message(&bot, ChatId(-100), "report".to_string(), None);
+        assert_eq!(request.payload_ref().message_thread_id, None);
+    }
+
+    #[test]
+    fn test_plain_fallback_on_entity_error() {
+        let entity_err = RequestError::Api(ApiError::CantParseEntities(
+            "Bad Request: can't parse entities: unexpected end of name token".to_string(),
+        ));
+        assert!(should_fallback_to_plain(&entity_err));
+
+        assert!(!should_fallback_to_plain(&RequestError::Api(
+            ApiError::ChatNotFound
+        )));
+        assert!(!should_fallback_to_plain(&RequestError::MigrateToChatId(
+            ChatId(-100)
+        )));
     }
 }
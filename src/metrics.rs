@@ -0,0 +1,83 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Runtime counters, shared between the watcher and the `/metrics` command.
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    files_processed: AtomicU64,
+    parse_failures: AtomicU64,
+    sends: AtomicU64,
+    send_failures: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            files_processed: AtomicU64::new(0),
+            parse_failures: AtomicU64::new(0),
+            sends: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn file_processed(&self) {
+        self.files_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failed(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) {
+        self.sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn files_processed(&self) -> u64 {
+        self.files_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> String {
+        let uptime = self.started.elapsed().as_secs();
+        format!(
+            "Files processed: {}\nParse failures: {}\nSends: {}\nSend failures: {}\nUptime: {}h {}m {}s",
+            self.files_processed.load(Ordering::Relaxed),
+            self.parse_failures.load(Ordering::Relaxed),
+            self.sends.load(Ordering::Relaxed),
+            self.send_failures.load(Ordering::Relaxed),
+            uptime / 3600,
+            uptime % 3600 / 60,
+            uptime % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_counts() {
+        let metrics = Metrics::default();
+        metrics.file_processed();
+        metrics.file_processed();
+        metrics.parse_failed();
+        metrics.sent();
+
+        let summary = metrics.summary();
+        assert!(summary.contains("Files processed: 2\n"));
+        assert!(summary.contains("Parse failures: 1\n"));
+        assert!(summary.contains("Sends: 1\n"));
+        assert!(summary.contains("Send failures: 0\n"));
+        assert!(summary.contains("Uptime: 0h 0m"));
+    }
+}
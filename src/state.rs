@@ -0,0 +1,317 @@
+use crate::error::BotError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use teloxide::types::ChatId;
+
+/// How many rendered reports `/match` can fetch back.
+const MAX_STORED_REPORTS: usize = 100;
+
+/// Everything the bot remembers between restarts.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub subscribed_chats: BTreeSet<i64>,
+    /// Last id handed out; every report gets the next one, shown as `#N`.
+    pub next_match_id: u64,
+    /// The most recent reports by match id, as they were sent.
+    pub reports: BTreeMap<u64, StoredReport>,
+    /// Groups upgraded to supergroups, from the old chat id to the new one.
+    pub migrated_chats: BTreeMap<i64, i64>,
+    /// Match files already handled, with the mtime they had then, so a
+    /// restart doesn't report them again unless they were rewritten.
+    pub processed_files: BTreeMap<PathBuf, SystemTime>,
+}
+
+/// A sent report, kept so `/match <id>` can post it again.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredReport {
+    /// Short description for listings, e.g. `TDM on q3dm6`.
+    pub title: String,
+    pub text: String,
+    /// Whether `text` is MarkdownV2 rather than plain text.
+    pub markdown: bool,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Self, BotError> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), BotError> {
+        // write-then-rename so a crash mid-write never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Returns `false` if the chat was already subscribed.
+    pub fn subscribe(&mut self, chat_id: ChatId) -> bool {
+        self.subscribed_chats.insert(chat_id.0)
+    }
+
+    /// Returns `false` if the chat wasn't subscribed.
+    pub fn unsubscribe(&mut self, chat_id: ChatId) -> bool {
+        self.subscribed_chats.remove(&chat_id.0)
+    }
+
+    /// Records that a group now lives on as a supergroup under `new`, moving
+    /// its subscription along.
+    pub fn migrate_chat(&mut self, old: ChatId, new: ChatId) {
+        self.migrated_chats.insert(old.0, new.0);
+        if self.subscribed_chats.remove(&old.0) {
+            self.subscribed_chats.insert(new.0);
+        }
+    }
+
+    /// The id to send to for `chat_id`, following any group upgrades.
+    pub fn resolve_chat(&self, chat_id: ChatId) -> ChatId {
+        let mut id = chat_id.0;
+        // bounded, so a corrupt state file with a cycle can't hang us
+        for _ in 0..self.migrated_chats.len() {
+            match self.migrated_chats.get(&id) {
+                Some(&new) => id = new,
+                None => break,
+            }
+        }
+        ChatId(id)
+    }
+
+    /// Where reports go: the `configured` chats, following any group
+    /// upgrades, then every subscribed chat, each once.
+    pub fn recipients(&self, configured: &[ChatId]) -> Vec<ChatId> {
+        let configured = configured.iter().map(|&id| self.resolve_chat(id));
+        let subscribed = self.subscribed_chats.iter().map(|&id| ChatId(id));
+        let mut chats: Vec<ChatId> = Vec::new();
+        for id in configured.chain(subscribed) {
+            if !chats.contains(&id) {
+                chats.push(id);
+            }
+        }
+        chats
+    }
+
+    /// Returns `false` if this version of the file was already processed.
+    pub fn mark_processed(&mut self, path: PathBuf, mtime: SystemTime) -> bool {
+        self.processed_files.insert(path, mtime) != Some(mtime)
+    }
+
+    /// Drops processed files that have since been deleted or rotated away,
+    /// so the list doesn't grow forever.
+    pub fn forget_missing_files(&mut self) {
+        self.processed_files.retain(|p, _| p.exists());
+    }
+
+    /// Hands out the next report number. Callers go through
+    /// `StateStore::update`, whose lock keeps concurrent reports apart.
+    pub fn next_report_id(&mut self) -> u64 {
+        self.next_match_id += 1;
+        self.next_match_id
+    }
+
+    /// Stores a report under its id, dropping the oldest once more than
+    /// `MAX_STORED_REPORTS` are kept.
+    pub fn archive_report(&mut self, id: u64, report: StoredReport) {
+        self.reports.insert(id, report);
+        while self.reports.len() > MAX_STORED_REPORTS {
+            self.reports.pop_first();
+        }
+    }
+}
+
+/// Shared handle to the state, saved to `path` after every change. Without a
+/// path the state only lives for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl StateStore {
+    pub fn open(path: Option<PathBuf>) -> Result<Self, BotError> {
+        let state = match &path {
+            Some(p) => State::load(p)?,
+            None => State::default(),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&State) -> R) -> R {
+        f(&self.state.lock().unwrap())
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> Result<R, BotError> {
+        let mut state = self.state.lock().unwrap();
+        let result = f(&mut state);
+        if let Some(path) = &self.path {
+            state.save(path)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_transitions() {
+        let mut state = State::default();
+        assert!(state.subscribe(ChatId(-100)));
+        assert!(!state.subscribe(ChatId(-100)));
+        assert!(state.subscribe(ChatId(42)));
+        assert_eq!(state.subscribed_chats, BTreeSet::from([-100, 42]));
+
+        assert!(state.unsubscribe(ChatId(-100)));
+        assert!(!state.unsubscribe(ChatId(-100)));
+        assert_eq!(state.subscribed_chats, BTreeSet::from([42]));
+    }
+
+    #[test]
+    fn test_migrated_chat_is_followed() {
+        let mut state = State::default();
+        state.subscribe(ChatId(-100));
+        state.subscribe(ChatId(42));
+        state.migrate_chat(ChatId(-100), ChatId(-1001));
+        state.migrate_chat(ChatId(-7), ChatId(-1007));
+
+        assert_eq!(state.subscribed_chats, BTreeSet::from([-1001, 42]));
+        assert_eq!(state.resolve_chat(ChatId(-100)), ChatId(-1001));
+        assert_eq!(state.resolve_chat(ChatId(-7)), ChatId(-1007));
+        assert_eq!(state.resolve_chat(ChatId(42)), ChatId(42));
+    }
+
+    #[test]
+    fn test_processed_files_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let kept = dir.path().join("kept.xml");
+        let gone = dir.path().join("gone.xml");
+        std::fs::write(&kept, "<match/>").unwrap();
+
+        let first = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60);
+        let rewritten = first + std::time::Duration::from_secs(60);
+
+        let store = StateStore::open(Some(path.clone())).unwrap();
+        assert!(store
+            .update(|s| s.mark_processed(kept.clone(), first))
+            .unwrap());
+        assert!(!store
+            .update(|s| s.mark_processed(kept.clone(), first))
+            .unwrap());
+        assert!(store
+            .update(|s| s.mark_processed(kept.clone(), rewritten))
+            .unwrap());
+        store
+            .update(|s| s.mark_processed(gone.clone(), first))
+            .unwrap();
+
+        let reloaded = StateStore::open(Some(path)).unwrap();
+        reloaded.read(|s| assert!(s.processed_files.contains_key(&gone)));
+        reloaded.update(State::forget_missing_files).unwrap();
+        reloaded.read(|s| assert_eq!(s.processed_files, BTreeMap::from([(kept, rewritten)])));
+    }
+
+    #[test]
+    fn test_corrupt_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{\"subscribed_chats\": [").unwrap();
+        assert!(matches!(
+            StateStore::open(Some(path)),
+            Err(BotError::State(_))
+        ));
+    }
+
+    #[test]
+    fn test_unreadable_state_path() {
+        let dir = tempfile::tempdir().unwrap();
+        // a directory can't be read as a file
+        assert!(matches!(
+            StateStore::open(Some(dir.path().to_path_buf())),
+            Err(BotError::Io(_))
+        ));
+
+        let store = StateStore::open(Some(dir.path().join("missing").join("state.json"))).unwrap();
+        assert!(matches!(
+            store.update(|s| s.subscribe(ChatId(1))),
+            Err(BotError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_archived_report_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let store = StateStore::open(Some(path.clone())).unwrap();
+        let report = StoredReport {
+            title: "TDM on q3dm6".to_string(),
+            text: "*Match concluded*".to_string(),
+            markdown: true,
+        };
+        store
+            .update(|s| {
+                let id = s.next_report_id();
+                s.archive_report(id, report.clone())
+            })
+            .unwrap();
+
+        let reloaded = StateStore::open(Some(path)).unwrap();
+        reloaded.read(|s| {
+            assert_eq!(s.reports.get(&1), Some(&report));
+            assert_eq!(s.reports.get(&2), None);
+        });
+    }
+
+    #[test]
+    fn test_archive_keeps_recent_reports() {
+        let mut state = State::default();
+        for _ in 0..MAX_STORED_REPORTS + 5 {
+            let id = state.next_report_id();
+            state.archive_report(id, StoredReport::default());
+        }
+        assert_eq!(state.reports.len(), MAX_STORED_REPORTS);
+        assert_eq!(state.reports.keys().next(), Some(&6));
+        assert_eq!(state.next_match_id, MAX_STORED_REPORTS as u64 + 5);
+    }
+
+    #[test]
+    fn test_report_numbers_survive_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let store = StateStore::open(Some(path.clone())).unwrap();
+        assert_eq!(store.update(State::next_report_id).unwrap(), 1);
+        assert_eq!(store.update(State::next_report_id).unwrap(), 2);
+
+        let reloaded = StateStore::open(Some(path)).unwrap();
+        assert_eq!(reloaded.update(State::next_report_id).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_subscriptions_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let store = StateStore::open(Some(path.clone())).unwrap();
+        store.update(|s| s.subscribe(ChatId(-100))).unwrap();
+        store.update(|s| s.subscribe(ChatId(7))).unwrap();
+        store.update(|s| s.unsubscribe(ChatId(7))).unwrap();
+
+        let reloaded = StateStore::open(Some(path)).unwrap();
+        reloaded.read(|s| assert_eq!(s.subscribed_chats, BTreeSet::from([-100])));
+    }
+}